@@ -4,18 +4,45 @@
 // Copyright: 2018, Crisp IM SAS
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{Error, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use crisp_status_local::probe::address::AddressFamily;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
 use super::defaults;
 
 #[derive(Deserialize)]
 pub struct Config {
     pub server: ConfigServer,
-    pub report: ConfigReport,
+
+    #[serde(deserialize_with = "deserialize_reports")]
+    pub report: Vec<ConfigReport>,
+
+    #[serde(default)]
+    pub probe: ConfigProbe,
+
+    #[serde(default)]
+    pub dns: ConfigDNS,
+
+    pub exec: Option<ConfigExec>,
+    pub secrets: Option<HashMap<String, ConfigSecret>>,
+    pub tls: Option<ConfigTLS>,
 }
 
 #[derive(Deserialize)]
 pub struct ConfigServer {
     #[serde(default = "defaults::server_log_level")]
     pub log_level: String,
+
+    pub crash_limit: Option<u32>,
+
+    #[serde(default = "defaults::server_crash_window")]
+    pub crash_window: u64,
 }
 
 #[derive(Deserialize)]
@@ -25,3 +52,160 @@ pub struct ConfigReport {
 
     pub token: String,
 }
+
+#[derive(Deserialize)]
+pub struct ConfigProbe {
+    #[serde(default = "defaults::probe_dead_after")]
+    pub dead_after: u32,
+
+    #[serde(default = "defaults::probe_sick_after")]
+    pub sick_after: u32,
+
+    #[serde(default = "defaults::probe_recover_after")]
+    pub recover_after: u32,
+
+    #[serde(default = "defaults::probe_flap_window")]
+    pub flap_window: u32,
+
+    #[serde(default = "defaults::probe_flap_threshold")]
+    pub flap_threshold: u32,
+
+    #[serde(default = "defaults::probe_family")]
+    pub family: AddressFamily,
+
+    pub bind_address: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigDNS {
+    #[serde(default = "defaults::dns_upstreams")]
+    pub upstreams: Vec<String>,
+
+    #[serde(default = "defaults::dns_timeout")]
+    pub timeout: u64,
+
+    #[serde(default = "defaults::dns_system_ttl")]
+    pub system_ttl: u64,
+
+    #[serde(default = "defaults::dns_maximum_ttl")]
+    pub maximum_ttl: u64,
+
+    #[serde(default = "defaults::dns_stale_ttl")]
+    pub stale_ttl: u64,
+}
+
+enum ConfigReports {
+    Single(ConfigReport),
+    Multiple(Vec<ConfigReport>),
+}
+
+#[derive(Deserialize)]
+pub struct ConfigExec {
+    #[serde(default = "defaults::exec_allow")]
+    pub allow: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigSecret {
+    pub path: PathBuf,
+    pub hosts: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigTLS {
+    #[serde(default = "defaults::tls_ca_bundles")]
+    pub ca_bundles: Vec<PathBuf>,
+
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+
+    #[serde(default = "defaults::tls_clients")]
+    pub clients: Vec<ConfigTLSClient>,
+
+    #[serde(default = "defaults::tls_insecure_skip_verify")]
+    pub insecure_skip_verify: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigTLSClient {
+    pub hosts: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+fn deserialize_reports<'de, D>(de: D) -> Result<Vec<ConfigReport>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // Notice: a single '[report]' table is still supported, next to '[[report]]' arrays (one \
+    //   entry per status page)
+    let reports = match ConfigReports::deserialize(de)? {
+        ConfigReports::Single(report) => vec![report],
+        ConfigReports::Multiple(reports) => reports,
+    };
+
+    if reports.is_empty() == true {
+        return Err(D::Error::custom("at least one report is required"));
+    }
+
+    Ok(reports)
+}
+
+impl<'de> Deserialize<'de> for ConfigReports {
+    fn deserialize<D>(de: D) -> Result<ConfigReports, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Notice: this is not an untagged enum, as errors would otherwise not tell what is wrong \
+        //   with a report (eg. a missing token), but only that it matched no variant.
+        struct ConfigReportsVisitor;
+
+        impl<'de> Visitor<'de> for ConfigReportsVisitor {
+            type Value = ConfigReports;
+
+            fn expecting(&self, format: &mut fmt::Formatter) -> fmt::Result {
+                write!(
+                    format,
+                    "a single '[report]' table, or '[[report]]' tables (one per status page)"
+                )
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<ConfigReports, A::Error> {
+                ConfigReport::deserialize(MapAccessDeserializer::new(map))
+                    .map(ConfigReports::Single)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<ConfigReports, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(ConfigReports::Multiple)
+            }
+        }
+
+        de.deserialize_any(ConfigReportsVisitor)
+    }
+}
+
+impl Default for ConfigProbe {
+    fn default() -> ConfigProbe {
+        ConfigProbe {
+            dead_after: defaults::probe_dead_after(),
+            sick_after: defaults::probe_sick_after(),
+            recover_after: defaults::probe_recover_after(),
+            flap_window: defaults::probe_flap_window(),
+            flap_threshold: defaults::probe_flap_threshold(),
+            family: defaults::probe_family(),
+            bind_address: None,
+        }
+    }
+}
+
+impl Default for ConfigDNS {
+    fn default() -> ConfigDNS {
+        ConfigDNS {
+            upstreams: defaults::dns_upstreams(),
+            timeout: defaults::dns_timeout(),
+            system_ttl: defaults::dns_system_ttl(),
+            maximum_ttl: defaults::dns_maximum_ttl(),
+            stale_ttl: defaults::dns_stale_ttl(),
+        }
+    }
+}
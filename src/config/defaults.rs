@@ -4,10 +4,81 @@
 // Copyright: 2018, Crisp IM SAS
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
+use crisp_status_local::probe::address::AddressFamily;
+use crisp_status_local::probe::report::REPORT_ENDPOINT_DEFAULT;
+
+use std::path::PathBuf;
+
+use super::config::ConfigTLSClient;
+
 pub fn server_log_level() -> String {
     "warn".to_string()
 }
 
+pub fn server_crash_window() -> u64 {
+    600
+}
+
 pub fn report_endpoint() -> String {
-    "https://report.crisp.watch/v1".to_string()
+    REPORT_ENDPOINT_DEFAULT.to_string()
+}
+
+pub fn probe_dead_after() -> u32 {
+    1
+}
+
+pub fn probe_sick_after() -> u32 {
+    1
+}
+
+pub fn probe_recover_after() -> u32 {
+    1
+}
+
+pub fn probe_flap_window() -> u32 {
+    0
+}
+
+pub fn probe_flap_threshold() -> u32 {
+    5
+}
+
+pub fn probe_family() -> AddressFamily {
+    AddressFamily::Any
+}
+
+pub fn dns_upstreams() -> Vec<String> {
+    Vec::new()
+}
+
+pub fn dns_timeout() -> u64 {
+    2
+}
+
+pub fn dns_system_ttl() -> u64 {
+    10
+}
+
+pub fn dns_maximum_ttl() -> u64 {
+    300
+}
+
+pub fn dns_stale_ttl() -> u64 {
+    300
+}
+
+pub fn exec_allow() -> Vec<String> {
+    Vec::new()
+}
+
+pub fn tls_ca_bundles() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+pub fn tls_clients() -> Vec<ConfigTLSClient> {
+    Vec::new()
+}
+
+pub fn tls_insecure_skip_verify() -> Vec<String> {
+    Vec::new()
 }
@@ -30,6 +30,29 @@ impl ConfigReader {
         // Parse configuration
         let config = toml::from_str(&conf).expect("syntax error in config file");
 
+        Self::validate(&config);
+
         config
     }
+
+    fn validate(config: &Config) {
+        // Notice: damping thresholds count cycles, so a threshold of zero would report any \
+        //   observation right away (eg. every sick replica as dead)
+        if config.probe.dead_after == 0
+            || config.probe.sick_after == 0
+            || config.probe.recover_after == 0
+        {
+            panic!("invalid damping threshold in config file (must be at least 1)");
+        }
+
+        // Notice: a crash limit of zero would exit upon the first crash, and an empty crash \
+        //   window would never count more than one crash
+        if config.server.crash_limit == Some(0) {
+            panic!("invalid crash limit in config file (must be at least 1)");
+        }
+
+        if config.server.crash_window == 0 {
+            panic!("invalid crash window in config file (must be more than 0)");
+        }
+    }
 }
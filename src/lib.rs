@@ -0,0 +1,49 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+#![allow(
+    clippy::bool_comparison,
+    clippy::let_and_return,
+    clippy::match_ref_pats,
+    clippy::module_inception,
+    clippy::needless_borrowed_reference,
+    clippy::needless_borrows_for_generic_args,
+    clippy::redundant_field_names,
+    clippy::redundant_static_lifetimes,
+    clippy::single_component_path_imports,
+    clippy::upper_case_acronyms
+)]
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate serde_derive;
+extern crate base64;
+#[cfg(feature = "probe-icmp")]
+extern crate fastping_rs;
+#[cfg(feature = "probe-grpc")]
+extern crate hpack;
+extern crate http_req;
+#[cfg(feature = "probe-exec")]
+extern crate libc;
+extern crate memmem;
+extern crate regex;
+#[cfg(feature = "probe-ssh")]
+extern crate ring;
+extern crate rustls;
+extern crate serde;
+extern crate serde_json;
+#[cfg(feature = "probe-websocket")]
+extern crate sha1;
+extern crate socket2;
+extern crate url;
+extern crate webpki_roots;
+
+pub mod probe;
+
+pub use probe::engine::{Engine, EngineBuilder, EngineError};
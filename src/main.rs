@@ -4,36 +4,50 @@
 // Copyright: 2018, Crisp IM SAS
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
+#![allow(
+    clippy::bool_comparison,
+    clippy::let_and_return,
+    clippy::match_ref_pats,
+    clippy::module_inception,
+    clippy::needless_borrowed_reference,
+    clippy::needless_borrows_for_generic_args,
+    clippy::redundant_field_names,
+    clippy::redundant_static_lifetimes,
+    clippy::single_component_path_imports,
+    clippy::upper_case_acronyms
+)]
+
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
-extern crate base64;
-extern crate fastping_rs;
-extern crate http_req;
-extern crate memmem;
+extern crate crisp_status_local;
 extern crate serde;
-extern crate serde_json;
 extern crate toml;
-extern crate url;
 
 mod config;
-mod probe;
 
+use std::cmp::min;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
+use std::process;
 use std::str::FromStr;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use clap::{Arg, Command};
+use crisp_status_local::probe::bind::Bind;
+use crisp_status_local::probe::manager::run as run_probe;
+use crisp_status_local::probe::poll::panic_reason;
+use crisp_status_local::probe::report::ReporterClient;
+use crisp_status_local::Engine;
 use log::LevelFilter;
 
 use config::config::Config;
 use config::logger::ConfigLogger;
 use config::reader::ConfigReader;
-use probe::manager::run as run_probe;
 
 struct AppArgs {
     config: String,
@@ -41,9 +55,14 @@ struct AppArgs {
 
 pub static THREAD_NAME_PROBE: &'static str = "crisp-status-local-probe";
 
+const SUPERVISOR_BACKOFF_INITIAL_SECONDS: u64 = 5;
+const SUPERVISOR_BACKOFF_MAXIMUM_SECONDS: u64 = 300;
+
 lazy_static! {
     static ref APP_ARGS: AppArgs = make_app_args();
     static ref APP_CONF: Config = ConfigReader::make();
+    static ref APP_ENGINE: Engine = make_app_engine();
+    static ref APP_REPORTERS: Vec<ReporterClient> = make_app_reporters();
 }
 
 fn make_app_args() -> AppArgs {
@@ -69,34 +88,152 @@ fn make_app_args() -> AppArgs {
     }
 }
 
+fn make_app_engine() -> Engine {
+    let mut builder = Engine::builder()
+        .dead_after(APP_CONF.probe.dead_after)
+        .sick_after(APP_CONF.probe.sick_after)
+        .recover_after(APP_CONF.probe.recover_after)
+        .flap_detection(APP_CONF.probe.flap_window, APP_CONF.probe.flap_threshold)
+        .family(APP_CONF.probe.family);
+
+    builder = builder
+        .resolver_timeout(Duration::from_secs(APP_CONF.dns.timeout))
+        .resolver_system_ttl(Duration::from_secs(APP_CONF.dns.system_ttl))
+        .resolver_maximum_ttl(Duration::from_secs(APP_CONF.dns.maximum_ttl))
+        .resolver_stale_ttl(Duration::from_secs(APP_CONF.dns.stale_ttl));
+
+    for upstream in &APP_CONF.dns.upstreams {
+        // Notice: upstreams may be set without a port, in which case the DNS port is used
+        let upstream = upstream
+            .parse::<SocketAddr>()
+            .or_else(|_| upstream.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+            .expect("invalid dns upstream");
+
+        builder = builder.resolver_upstream(upstream);
+    }
+
+    if let Some(ref bind_address) = APP_CONF.probe.bind_address {
+        builder = builder.bind(Bind::parse(bind_address).expect("invalid probe bind address"));
+    }
+
+    if let Some(ref exec) = APP_CONF.exec {
+        for command in &exec.allow {
+            builder = builder.exec_allow(command.as_str());
+        }
+    }
+
+    if let Some(ref secrets) = APP_CONF.secrets {
+        for (name, secret) in secrets {
+            builder = builder.secret(name.as_str(), secret.path.as_path(), secret.hosts.clone());
+        }
+    }
+
+    if let Some(ref tls) = APP_CONF.tls {
+        for ca_bundle in &tls.ca_bundles {
+            builder = builder.tls_ca_bundle(ca_bundle.as_path());
+        }
+
+        if let (Some(ref cert), Some(ref key)) = (&tls.client_cert, &tls.client_key) {
+            builder = builder.tls_client(cert.as_path(), key.as_path());
+        }
+
+        for client in &tls.clients {
+            builder = builder.tls_host_client(
+                client.hosts.clone(),
+                client.cert.as_path(),
+                client.key.as_path(),
+            );
+        }
+
+        for host in &tls.insecure_skip_verify {
+            builder = builder.tls_insecure_skip_verify(host.as_str());
+        }
+
+        // Notice: an empty '[tls]' section still enables the local TLS configuration
+        builder = builder.tls();
+    }
+
+    builder.build()
+}
+
+fn make_app_reporters() -> Vec<ReporterClient> {
+    APP_CONF
+        .report
+        .iter()
+        .map(|report| ReporterClient::new(report.endpoint.as_str(), &report.token))
+        .collect()
+}
+
 fn ensure_states() {
     // Ensure all statics are valid (a `deref` is enough to lazily initialize them)
-    let (_, _) = (APP_ARGS.deref(), APP_CONF.deref());
+    let (_, _, _, _) = (
+        APP_ARGS.deref(),
+        APP_CONF.deref(),
+        APP_ENGINE.deref(),
+        APP_REPORTERS.deref(),
+    );
 }
 
-fn spawn_probe() {
-    debug!("spawn managed thread: probe");
+fn supervise_probe() {
+    let crash_window = Duration::from_secs(APP_CONF.server.crash_window);
+
+    // Crash times within the crash window (used to compute the backoff delay)
+    let mut crashes: Vec<Instant> = Vec::new();
+
+    loop {
+        debug!("spawn managed thread: probe");
 
-    let worker = thread::Builder::new()
-        .name(THREAD_NAME_PROBE.to_string())
-        .spawn(run_probe);
+        let worker = thread::Builder::new()
+            .name(THREAD_NAME_PROBE.to_string())
+            .spawn(|| run_probe(&APP_ENGINE, &APP_REPORTERS));
 
-    // Block on worker thread (join it)
-    let has_error = if let Ok(worker_thread) = worker {
-        worker_thread.join().is_err()
-    } else {
-        true
-    };
+        // Block on worker thread (join it)
+        let reason = match worker {
+            Ok(worker_thread) => match worker_thread.join() {
+                Ok(_) => return,
+                Err(payload) => panic_reason(&*payload),
+            },
+            Err(err) => format!("could not spawn thread: {}", err),
+        };
 
-    // Worker thread crashed?
-    if has_error == true {
-        error!("managed thread crashed (probe), setting it up again");
+        // Worker thread crashed
+        let now = Instant::now();
+
+        APP_ENGINE.count_crash();
+
+        crashes.retain(|crash| now.duration_since(*crash) < crash_window);
+        crashes.push(now);
+
+        error!(
+            "managed thread crashed (probe): {} ({} crashes in the last {}s)",
+            reason,
+            crashes.len(),
+            crash_window.as_secs()
+        );
+
+        // Crashing too often? Exit and let the service manager restart the process
+        if let Some(crash_limit) = APP_CONF.server.crash_limit {
+            if crashes.len() >= crash_limit as usize {
+                error!(
+                    "managed thread crashed (probe) too many times, exiting (limit: {})",
+                    crash_limit
+                );
+
+                process::exit(1);
+            }
+        }
 
         // Prevents thread start loop floods
-        // Notice: 5 seconds here to prevent network floods
-        thread::sleep(Duration::from_secs(5));
+        // Notice: the delay doubles upon each crash in the crash window, to prevent network \
+        //   floods when the thread keeps crashing right after it starts.
+        let backoff = min(
+            SUPERVISOR_BACKOFF_INITIAL_SECONDS.saturating_mul(1 << min(crashes.len() - 1, 16)),
+            SUPERVISOR_BACKOFF_MAXIMUM_SECONDS,
+        );
+
+        info!("setting up managed thread (probe) again in {}s", backoff);
 
-        spawn_probe();
+        thread::sleep(Duration::from_secs(backoff));
     }
 }
 
@@ -111,8 +248,8 @@ fn main() {
     // Ensure all states are bound
     ensure_states();
 
-    // Spawn probe (foreground thread)
-    spawn_probe();
+    // Spawn probe (foreground thread, supervised)
+    supervise_probe();
 
     error!("could not start");
 }
@@ -0,0 +1,356 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::engine::EngineError;
+use super::prober::ProbeOutcome;
+use super::status::Status;
+
+#[derive(Debug, Clone, Copy)]
+pub enum AddressPolicy {
+    First,
+    Any,
+    All,
+    Quorum(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    Any,
+    IPv4,
+    IPv6,
+    Both,
+}
+
+impl AddressPolicy {
+    pub fn parse(value: &str) -> Result<AddressPolicy, EngineError> {
+        match value {
+            "first" => Ok(AddressPolicy::First),
+            "any" => Ok(AddressPolicy::Any),
+            "all" => Ok(AddressPolicy::All),
+            _ => match value.strip_prefix("quorum=").map(|count| count.parse()) {
+                Some(Ok(count)) if count > 0 => Ok(AddressPolicy::Quorum(count)),
+                _ => Err(EngineError::InvalidAddressPolicy),
+            },
+        }
+    }
+
+    #[cfg(feature = "probe-connect")]
+    pub(crate) fn from_options(options: &[(String, String)]) -> Result<AddressPolicy, ()> {
+        match options.iter().find(|(key, _)| key == "addresses") {
+            Some((_, value)) => Self::parse(value).or(Err(())),
+            None => Ok(AddressPolicy::First),
+        }
+    }
+
+    pub fn probe<F>(&self, addresses: Vec<SocketAddr>, probe_address: F) -> ProbeOutcome
+    where
+        F: Fn(SocketAddr) -> ProbeOutcome + Sync,
+    {
+        // Only probe the first resolved address? (this is the default policy)
+        let addresses: Vec<SocketAddr> = match self {
+            AddressPolicy::First => addresses.into_iter().take(1).collect(),
+            _ => addresses,
+        };
+
+        if addresses.is_empty() == true {
+            return ProbeOutcome::dead("no address resolved");
+        }
+
+        // Probe all addresses at once (a replica with many unreachable addresses would \
+        //   otherwise take the sum of their timeouts to probe)
+        let outcomes: Vec<(SocketAddr, ProbeOutcome, Duration)> = if addresses.len() == 1 {
+            addresses
+                .into_iter()
+                .map(|address| probe_address_timed(address, &probe_address))
+                .collect()
+        } else {
+            thread::scope(|scope| {
+                let probe_address = &probe_address;
+
+                let handles: Vec<_> = addresses
+                    .into_iter()
+                    .map(|address| {
+                        (
+                            address,
+                            scope.spawn(move || probe_address_timed(address, probe_address)),
+                        )
+                    })
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .map(|(address, handle)| {
+                        handle.join().unwrap_or_else(|_| {
+                            (
+                                address,
+                                ProbeOutcome::dead("probe panicked"),
+                                Duration::ZERO,
+                            )
+                        })
+                    })
+                    .collect()
+            })
+        };
+
+        // Notice: the latency reported is the one of the slowest address that passed, as the \
+        //   latency of an address that timed out would otherwise be compared to the sick delay, \
+        //   even though the policy passed without it (the slowest address is used if none passed)
+        let (total, mut passed, mut sick) = (outcomes.len(), 0, 0);
+        let (mut latency_passed, mut latency_any) = (None, None);
+        let mut reasons = Vec::new();
+
+        for (address, outcome, address_latency) in outcomes {
+            debug!(
+                "got address probe outcome for: {} with status: {:?}",
+                address, outcome.status
+            );
+
+            latency_any = Some(latency_any.map_or(address_latency, |latency: Duration| {
+                latency.max(address_latency)
+            }));
+
+            match outcome.status {
+                Status::Healthy => passed += 1,
+                Status::Sick => {
+                    passed += 1;
+                    sick += 1;
+                }
+                Status::Dead => {}
+            }
+
+            if outcome.status != Status::Dead {
+                latency_passed =
+                    Some(latency_passed.map_or(address_latency, |latency: Duration| {
+                        latency.max(address_latency)
+                    }));
+            }
+
+            if let Some(reason) = outcome.reason {
+                // Notice: for single address outcomes, the reason is passed through as-is
+                if total == 1 {
+                    reasons.push(reason);
+                } else {
+                    reasons.push(format!("{}: {}", address, reason));
+                }
+            }
+        }
+
+        let reason = if total == 1 {
+            reasons.pop().unwrap_or_default()
+        } else {
+            format!(
+                "{}/{} addresses passed ({})",
+                passed,
+                total,
+                reasons.join(", ")
+            )
+        };
+
+        let status = match self.evaluate(passed, total) {
+            Status::Healthy if sick > 0 => Status::Sick,
+            status => status,
+        };
+
+        let mut outcome = ProbeOutcome::from_status(status, reason);
+
+        outcome.latency = latency_passed.or(latency_any);
+
+        outcome
+    }
+
+    fn evaluate(&self, passed: usize, total: usize) -> Status {
+        // Report as sick when some, but not enough addresses passed
+        let required = match self {
+            AddressPolicy::First | AddressPolicy::Any => 1,
+            AddressPolicy::All => total,
+            // Notice: a quorum larger than the number of resolved addresses is clamped, as the \
+            //   replica could otherwise never be reported as healthy
+            AddressPolicy::Quorum(count) => (*count).min(total),
+        };
+
+        if passed >= required {
+            Status::Healthy
+        } else if passed > 0 {
+            Status::Sick
+        } else {
+            Status::Dead
+        }
+    }
+}
+
+impl AddressFamily {
+    pub fn parse(value: &str) -> Result<AddressFamily, EngineError> {
+        match value {
+            "any" => Ok(AddressFamily::Any),
+            "ipv4" => Ok(AddressFamily::IPv4),
+            "ipv6" => Ok(AddressFamily::IPv6),
+            "both" => Ok(AddressFamily::Both),
+            _ => Err(EngineError::InvalidAddressFamily),
+        }
+    }
+
+    #[cfg(feature = "probe-net")]
+    pub(crate) fn from_options(options: &[(String, String)]) -> Result<Option<AddressFamily>, ()> {
+        // Notice: when not set on the replica, the family configured on the engine is used
+        match options.iter().find(|(key, _)| key == "family") {
+            Some((_, value)) => Self::parse(value).map(Some).or(Err(())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn probe<F>(&self, addresses: Vec<SocketAddr>, probe_family: F) -> ProbeOutcome
+    where
+        F: Fn(Vec<SocketAddr>) -> ProbeOutcome,
+    {
+        let (addresses_v4, addresses_v6): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addresses.iter().partition(|address| address.is_ipv4());
+
+        match self {
+            AddressFamily::Any => probe_family(addresses),
+            AddressFamily::IPv4 => probe_family_addresses("ipv4", addresses_v4, &probe_family),
+            AddressFamily::IPv6 => probe_family_addresses("ipv6", addresses_v6, &probe_family),
+            // Notice: hosts that only resolve to a single family are probed over that family
+            AddressFamily::Both if addresses_v6.is_empty() == true => probe_family(addresses_v4),
+            AddressFamily::Both if addresses_v4.is_empty() == true => probe_family(addresses_v6),
+            AddressFamily::Both => {
+                // Probe each family separately (a replica that only works over one family is \
+                //   reported as sick, eg. when its IPv6 connectivity is broken)
+                let outcome_v4 = probe_family_addresses("ipv4", addresses_v4, &probe_family);
+                let outcome_v6 = probe_family_addresses("ipv6", addresses_v6, &probe_family);
+
+                let status = match (outcome_v4.status, outcome_v6.status) {
+                    (Status::Healthy, Status::Healthy) => Status::Healthy,
+                    (Status::Dead, Status::Dead) => Status::Dead,
+                    _ => Status::Sick,
+                };
+
+                let reasons: Vec<String> = [("ipv4", &outcome_v4), ("ipv6", &outcome_v6)]
+                    .iter()
+                    .filter_map(|(family, outcome)| {
+                        outcome
+                            .reason
+                            .as_ref()
+                            .map(|reason| format!("{}: {}", family, reason))
+                    })
+                    .collect();
+
+                let mut outcome = ProbeOutcome::from_status(status, reasons.join(", "));
+
+                outcome.latency = match (outcome_v4.latency, outcome_v6.latency) {
+                    (Some(latency_v4), Some(latency_v6)) => Some(latency_v4.max(latency_v6)),
+                    (latency_v4, latency_v6) => latency_v4.or(latency_v6),
+                };
+
+                outcome
+            }
+        }
+    }
+}
+
+fn probe_address_timed<F>(
+    address: SocketAddr,
+    probe_address: &F,
+) -> (SocketAddr, ProbeOutcome, Duration)
+where
+    F: Fn(SocketAddr) -> ProbeOutcome,
+{
+    let start_time = Instant::now();
+    let outcome = probe_address(address);
+    let latency = outcome.latency.unwrap_or_else(|| start_time.elapsed());
+
+    (address, outcome, latency)
+}
+
+fn probe_family_addresses<F>(
+    family: &str,
+    addresses: Vec<SocketAddr>,
+    probe_family: &F,
+) -> ProbeOutcome
+where
+    F: Fn(Vec<SocketAddr>) -> ProbeOutcome,
+{
+    if addresses.is_empty() == true {
+        ProbeOutcome::dead(format!("no {} address resolved", family))
+    } else {
+        probe_family(addresses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(count: u8) -> Vec<SocketAddr> {
+        (1..=count)
+            .map(|index| SocketAddr::from(([192, 0, 2, index], 80)))
+            .collect()
+    }
+
+    fn outcome(status: Status, latency: Duration) -> ProbeOutcome {
+        let mut outcome = ProbeOutcome::from_status(status, "");
+
+        outcome.latency = Some(latency);
+
+        outcome
+    }
+
+    #[test]
+    fn it_reports_latency_of_passing_addresses() {
+        // The first address times out, while the second one answers quickly
+        let outcome = AddressPolicy::Any.probe(addresses(2), |address| {
+            if address.ip().to_string() == "192.0.2.1" {
+                outcome(Status::Dead, Duration::from_secs(10))
+            } else {
+                outcome(Status::Healthy, Duration::from_millis(10))
+            }
+        });
+
+        assert_eq!(outcome.status, Status::Healthy);
+        assert_eq!(outcome.latency, Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn it_reports_latency_of_slowest_address_if_none_passed() {
+        let addresses = vec![
+            SocketAddr::from(([192, 0, 2, 1], 2)),
+            SocketAddr::from(([192, 0, 2, 2], 9)),
+        ];
+
+        let outcome = AddressPolicy::All.probe(addresses, |address| {
+            outcome(Status::Dead, Duration::from_secs(address.port() as u64))
+        });
+
+        assert_eq!(outcome.status, Status::Dead);
+        assert_eq!(outcome.latency, Some(Duration::from_secs(9)));
+    }
+
+    #[test]
+    fn it_probes_addresses_concurrently() {
+        let start_time = Instant::now();
+
+        let outcome = AddressPolicy::All.probe(addresses(5), |_| {
+            thread::sleep(Duration::from_millis(200));
+
+            ProbeOutcome::healthy()
+        });
+
+        assert_eq!(outcome.status, Status::Healthy);
+        assert!(start_time.elapsed() < Duration::from_millis(800));
+    }
+
+    #[test]
+    fn it_passes_single_address_reason_through() {
+        let outcome =
+            AddressPolicy::First.probe(addresses(2), |_| ProbeOutcome::dead("connection failed"));
+
+        assert_eq!(outcome.reason, Some("connection failed".to_string()));
+    }
+}
@@ -0,0 +1,176 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use super::engine::EngineError;
+
+// Notice: interface names are limited to 15 characters on Linux (ie. 'IFNAMSIZ' minus the \
+//   trailing null byte)
+const BIND_DEVICE_NAME_MAXIMUM_LENGTH: usize = 15;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bind {
+    Address(IpAddr),
+    Device(String),
+}
+
+impl Bind {
+    pub fn parse(value: &str) -> Result<Bind, EngineError> {
+        // Bind to a local IP address? (otherwise, bind to a network interface)
+        if let Ok(address) = value.parse() {
+            return Ok(Bind::Address(address));
+        }
+
+        // Notice: values that look like an IP address but could not be parsed as one (eg. \
+        //   '10.0.0.300') are rejected, as they would otherwise be taken as an interface name.
+        if looks_like_address(value) == true {
+            return Err(EngineError::InvalidBind);
+        }
+
+        if value.is_empty() == false
+            && value.len() <= BIND_DEVICE_NAME_MAXIMUM_LENGTH
+            && value.contains(|character: char| character == '/' || character.is_whitespace())
+                == false
+        {
+            Ok(Bind::Device(value.to_owned()))
+        } else {
+            Err(EngineError::InvalidBind)
+        }
+    }
+
+    #[cfg(feature = "probe-net")]
+    pub(crate) fn from_options(options: &[(String, String)]) -> Result<Option<Bind>, ()> {
+        // Notice: when not set on the replica, the bind configured on the engine is used
+        match options.iter().find(|(key, _)| key == "bind") {
+            Some((_, value)) => Self::parse(value).map(Some).or(Err(())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn accepts(&self, address: &SocketAddr) -> bool {
+        // Notice: a socket bound to a local IP address can only connect to addresses of the \
+        //   same family, while an interface can be used for any family.
+        match self {
+            Bind::Address(ip) => ip.is_ipv4() == address.is_ipv4(),
+            Bind::Device(_) => true,
+        }
+    }
+
+    pub fn apply(&self, socket: &Socket, address: &SocketAddr) -> io::Result<()> {
+        match self {
+            Bind::Address(ip) => {
+                if ip.is_ipv4() != address.is_ipv4() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("cannot bind to: {} for target: {}", ip, address.ip()),
+                    ));
+                }
+
+                socket.bind(&SocketAddr::new(*ip, 0).into())
+            }
+            Bind::Device(device) => bind_device(socket, device),
+        }
+    }
+}
+
+pub fn connect(
+    bind: Option<&Bind>,
+    address: &SocketAddr,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    // Connect from the default source address? (as picked by the system)
+    let bind = match bind {
+        Some(bind) => bind,
+        None => return TcpStream::connect_timeout(address, timeout),
+    };
+
+    let socket = Socket::new(
+        Domain::for_address(*address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+
+    bind.apply(&socket, address)?;
+    socket.connect_timeout(&(*address).into(), timeout)?;
+
+    Ok(socket.into())
+}
+
+fn looks_like_address(value: &str) -> bool {
+    let is_ipv4_like = value.contains('.') == true
+        && value
+            .chars()
+            .all(|character| character.is_ascii_digit() || character == '.');
+
+    let is_ipv6_like = value.contains(':') == true
+        && value
+            .chars()
+            .all(|character| character.is_ascii_hexdigit() || character == ':' || character == '.');
+
+    is_ipv4_like || is_ipv6_like
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, device: &str) -> io::Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot bind to interface: {} on this platform", device),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_addresses_and_devices() {
+        assert_eq!(
+            Bind::parse("10.0.0.5"),
+            Ok(Bind::Address("10.0.0.5".parse().unwrap()))
+        );
+        assert_eq!(
+            Bind::parse("fd00::5"),
+            Ok(Bind::Address("fd00::5".parse().unwrap()))
+        );
+        assert_eq!(Bind::parse("eth1"), Ok(Bind::Device("eth1".to_string())));
+        assert_eq!(
+            Bind::parse("eth0:1"),
+            Ok(Bind::Device("eth0:1".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        assert_eq!(Bind::parse("10.0.0.300"), Err(EngineError::InvalidBind));
+        assert_eq!(Bind::parse("10.0.0"), Err(EngineError::InvalidBind));
+        assert_eq!(Bind::parse("fd00::5::1"), Err(EngineError::InvalidBind));
+    }
+
+    #[test]
+    fn accepts_addresses_of_bind_family() {
+        let (target_v4, target_v6) = (
+            "192.0.2.1:80".parse().unwrap(),
+            "[2001:db8::1]:80".parse().unwrap(),
+        );
+
+        let bind_v4 = Bind::parse("10.0.0.5").unwrap();
+        let bind_device = Bind::parse("eth1").unwrap();
+
+        assert!(bind_v4.accepts(&target_v4));
+        assert!(!bind_v4.accepts(&target_v6));
+        assert!(bind_device.accepts(&target_v6));
+    }
+}
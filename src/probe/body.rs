@@ -0,0 +1,380 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use memmem::{Searcher, TwoWaySearcher};
+use serde_json::{self, Value};
+
+use super::map::{
+    MapServiceNodeHTTP, MapServiceNodeHTTPBodyJSONRule, MapServiceNodeHTTPBodyJSONRuleFailure,
+    MapServiceNodeHTTPBodyRegexes,
+};
+use super::prober::ProbeOutcome;
+use super::status::Status;
+
+fn acquire_http_body_matches(
+    http: &Option<MapServiceNodeHTTP>,
+) -> (Option<String>, Option<String>, Option<String>) {
+    let (mut http_body_healthy_match, mut http_body_dead_match, mut http_body_sick_match) =
+        (None, None, None);
+
+    if let Some(ref http_inner) = http {
+        if let Some(ref http_inner_body_inner) = http_inner.body {
+            if let Some(ref healthy_match_inner) = http_inner_body_inner.healthy_match {
+                if healthy_match_inner.is_empty() == false {
+                    http_body_healthy_match = Some(healthy_match_inner.to_owned());
+                }
+            }
+
+            if let Some(ref dead_match_inner) = http_inner_body_inner.dead_match {
+                if dead_match_inner.is_empty() == false {
+                    http_body_dead_match = Some(dead_match_inner.to_owned());
+                }
+            }
+
+            if let Some(ref sick_match_inner) = http_inner_body_inner.sick_match {
+                if sick_match_inner.is_empty() == false {
+                    http_body_sick_match = Some(sick_match_inner.to_owned());
+                }
+            }
+        }
+    }
+
+    (
+        http_body_healthy_match,
+        http_body_dead_match,
+        http_body_sick_match,
+    )
+}
+
+fn acquire_http_body_regexes(
+    http: &Option<MapServiceNodeHTTP>,
+) -> Option<&MapServiceNodeHTTPBodyRegexes> {
+    http.as_ref()
+        .and_then(|http_inner| http_inner.body.as_ref())
+        .map(|body_inner| &body_inner.regexes)
+        .filter(|regexes| {
+            regexes.healthy.is_some() == true
+                || regexes.dead.is_some() == true
+                || regexes.sick.is_some() == true
+                || regexes.invalid == true
+        })
+}
+
+fn acquire_http_body_json_rules(
+    http: &Option<MapServiceNodeHTTP>,
+) -> Option<&[MapServiceNodeHTTPBodyJSONRule]> {
+    http.as_ref()
+        .and_then(|http_inner| http_inner.body.as_ref())
+        .and_then(|body_inner| body_inner.json_rules.as_deref())
+        .filter(|json_rules| json_rules.is_empty() == false)
+}
+
+pub fn has_http_body_checks(http: &Option<MapServiceNodeHTTP>) -> bool {
+    let (http_body_healthy_match, http_body_dead_match, http_body_sick_match) =
+        acquire_http_body_matches(http);
+
+    http_body_healthy_match.is_some() == true
+        || http_body_dead_match.is_some() == true
+        || http_body_sick_match.is_some() == true
+        || acquire_http_body_regexes(http).is_some() == true
+        || acquire_http_body_json_rules(http).is_some() == true
+}
+
+pub fn check_http_body(
+    url: &str,
+    response_body: &[u8],
+    http: &Option<MapServiceNodeHTTP>,
+    subject: &str,
+) -> ProbeOutcome {
+    // Notice: checks are applied in order, any dead check returning early; the reason always \
+    //   tells which check failed (the subject being eg. 'response body', or 'reply').
+    let (http_body_healthy_match, http_body_dead_match, http_body_sick_match) =
+        acquire_http_body_matches(http);
+    let http_body_regexes = acquire_http_body_regexes(http);
+
+    // Any invalid regex? Consider as DOWN (the response body cannot be checked as configured)
+    if http_body_regexes
+        .map(|regexes| regexes.invalid)
+        .unwrap_or(false)
+        == true
+    {
+        return ProbeOutcome::dead("invalid regex");
+    }
+
+    // Check response body for matches? (if configured)
+    if let Err(reason) = check_http_body_matches(
+        url,
+        response_body,
+        &http_body_healthy_match,
+        &http_body_dead_match,
+    ) {
+        return ProbeOutcome::dead(format!("{} {}", subject, reason));
+    }
+
+    // Check response body against regexes? (if configured)
+    let mut outcome = ProbeOutcome::healthy();
+
+    if let Some(http_body_regexes) = http_body_regexes {
+        match check_http_body_regexes(url, response_body, http_body_regexes) {
+            (Status::Dead, reason) => return ProbeOutcome::dead(format!("{} {}", subject, reason)),
+            (status, reason) => {
+                outcome = ProbeOutcome::from_status(status, format!("{} {}", subject, reason))
+            }
+        }
+    }
+
+    // Check response body against JSON rules? (if configured)
+    if let Some(http_body_json_rules) = acquire_http_body_json_rules(http) {
+        match check_http_body_json(url, response_body, http_body_json_rules) {
+            (Status::Healthy, _) => {}
+            (Status::Dead, reason) => return ProbeOutcome::dead(format!("{} {}", subject, reason)),
+            (status, reason) => {
+                if let Status::Healthy = outcome.status {
+                    outcome = ProbeOutcome::from_status(status, format!("{} {}", subject, reason));
+                }
+            }
+        }
+    }
+
+    // Check response body for sick match? (if configured)
+    if let Status::Healthy = outcome.status {
+        if check_http_body_sick(url, response_body, &http_body_sick_match) == true {
+            return ProbeOutcome::sick(format!("{} has sick match", subject));
+        }
+    }
+
+    outcome
+}
+
+fn check_http_body_matches(
+    url: &str,
+    response_body: &[u8],
+    http_body_healthy_match: &Option<String>,
+    http_body_dead_match: &Option<String>,
+) -> Result<(), &'static str> {
+    // Check response body for dead match? (if configured)
+    if let Some(ref http_body_dead_match_inner) = http_body_dead_match {
+        if !response_body.is_empty() {
+            debug!(
+                "checking prober poll result response text for url: {} for dead match",
+                &url
+            );
+
+            // Matches? Consider as DOWN.
+            let text_search =
+                TwoWaySearcher::new(http_body_dead_match_inner.as_bytes()).search_in(response_body);
+
+            if text_search.is_some() {
+                return Err("has dead match");
+            }
+        }
+    }
+
+    // Check response body for healthy match? (if configured)
+    if let Some(ref http_body_healthy_match_inner) = http_body_healthy_match {
+        if !response_body.is_empty() {
+            debug!(
+                "checking prober poll result response text for url: {} for healthy match",
+                &url
+            );
+
+            // Doesnt match? Consider as DOWN.
+            let text_search = TwoWaySearcher::new(http_body_healthy_match_inner.as_bytes())
+                .search_in(response_body);
+
+            if text_search.is_none() {
+                return Err("does not have healthy match");
+            }
+        } else {
+            debug!("could not unpack response text for url: {}", &url);
+
+            // Consider as DOWN (the response text could not be checked)
+            return Err("is empty");
+        }
+    }
+
+    Ok(())
+}
+
+fn check_http_body_sick(
+    url: &str,
+    response_body: &[u8],
+    http_body_sick_match: &Option<String>,
+) -> bool {
+    // Check response body for sick match? (if configured)
+    if let Some(ref http_body_sick_match_inner) = http_body_sick_match {
+        if !response_body.is_empty() {
+            debug!(
+                "checking prober poll result response text for url: {} for sick match",
+                &url
+            );
+
+            // Matches? Consider as SICK.
+            let text_search =
+                TwoWaySearcher::new(http_body_sick_match_inner.as_bytes()).search_in(response_body);
+
+            return text_search.is_some();
+        }
+    }
+
+    false
+}
+
+fn check_http_body_regexes(
+    url: &str,
+    response_body: &[u8],
+    regexes: &MapServiceNodeHTTPBodyRegexes,
+) -> (Status, &'static str) {
+    debug!(
+        "checking prober poll result response text for url: {} against regexes",
+        &url
+    );
+
+    // Matches dead regex? Consider as DOWN.
+    if let Some(ref dead_regex) = regexes.dead {
+        if dead_regex.is_match(response_body) == true {
+            return (Status::Dead, "matches dead regex");
+        }
+    }
+
+    // Does not match healthy regex? Consider as DOWN.
+    if let Some(ref healthy_regex) = regexes.healthy {
+        if healthy_regex.is_match(response_body) == false {
+            return (Status::Dead, "does not match healthy regex");
+        }
+    }
+
+    // Matches sick regex? Consider as SICK.
+    if let Some(ref sick_regex) = regexes.sick {
+        if sick_regex.is_match(response_body) == true {
+            return (Status::Sick, "matches sick regex");
+        }
+    }
+
+    (Status::Healthy, "")
+}
+
+fn check_http_body_json(
+    url: &str,
+    response_body: &[u8],
+    json_rules: &[MapServiceNodeHTTPBodyJSONRule],
+) -> (Status, String) {
+    debug!(
+        "checking prober poll result response text for url: {} against json rules",
+        &url
+    );
+
+    // Parse response body (consider as DOWN if not valid JSON)
+    let document = match serde_json::from_slice::<Value>(response_body) {
+        Ok(document) => document,
+        Err(err) => {
+            debug!(
+                "could not parse response text as json for url: {} (error: {})",
+                &url, err
+            );
+
+            return (Status::Dead, "is not valid json".to_string());
+        }
+    };
+
+    // Evaluate all rules (a failing dead rule takes precedence over a failing sick rule)
+    // Notice: rules are parsed once when the map is acquired; invalid rules always fail.
+    let mut result = (Status::Healthy, String::new());
+
+    for json_rule in json_rules {
+        let is_passing = json_rule
+            .parsed
+            .as_ref()
+            .map(|rule| rule.evaluate(&document))
+            .unwrap_or(false);
+
+        if is_passing == false {
+            debug!("json rule failed for url: {}: {}", &url, json_rule.rule);
+
+            let reason = if json_rule.parsed.is_some() == true {
+                format!("fails json rule: {}", json_rule.rule)
+            } else {
+                format!(
+                    "cannot be checked against invalid json rule: {}",
+                    json_rule.rule
+                )
+            };
+
+            match json_rule
+                .failure
+                .unwrap_or(MapServiceNodeHTTPBodyJSONRuleFailure::Dead)
+            {
+                MapServiceNodeHTTPBodyJSONRuleFailure::Dead => return (Status::Dead, reason),
+                MapServiceNodeHTTPBodyJSONRuleFailure::Sick => {
+                    if let Status::Healthy = result.0 {
+                        result = (Status::Sick, reason);
+                    }
+                }
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::map::{compile_rules, MapService};
+
+    fn http(body: &str) -> Option<MapServiceNodeHTTP> {
+        let mut services: Vec<MapService> = vec![serde_json::from_str(&format!(
+            r#"{{"id": "service", "nodes": [{{"id": "node", "http": {{"body": {}}}}}]}}"#,
+            body
+        ))
+        .unwrap()];
+
+        compile_rules(&mut services);
+
+        services.remove(0).nodes.remove(0).http
+    }
+
+    fn check(http: &Option<MapServiceNodeHTTP>, body: &str) -> (Status, Option<String>) {
+        let outcome = check_http_body("http://node.local/", body.as_bytes(), http, "body");
+
+        (outcome.status, outcome.reason)
+    }
+
+    #[test]
+    fn it_checks_regexes() {
+        let http = http(r#"{"healthy_regex": "^ok \\d+$", "dead_regex": "fatal"}"#);
+
+        assert_eq!(check(&http, "ok 42"), (Status::Healthy, None));
+        assert_eq!(
+            check(&http, "ok"),
+            (
+                Status::Dead,
+                Some("body does not match healthy regex".to_string())
+            )
+        );
+        assert_eq!(
+            check(&http, "fatal error"),
+            (Status::Dead, Some("body matches dead regex".to_string()))
+        );
+    }
+
+    #[test]
+    fn it_reports_invalid_regex_as_dead() {
+        let http = http(r#"{"healthy_regex": "ok", "sick_regex": "(unclosed"}"#);
+
+        let regexes = &http.as_ref().unwrap().body.as_ref().unwrap().regexes;
+
+        assert!(regexes.invalid);
+        assert!(regexes.healthy.is_some());
+        assert!(has_http_body_checks(&http));
+
+        assert_eq!(
+            check(&http, "ok"),
+            (Status::Dead, Some("invalid regex".to_string()))
+        );
+    }
+}
@@ -0,0 +1,274 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::collections::{HashMap, VecDeque};
+
+use super::status::Status;
+
+pub struct Damping {
+    cycle: u64,
+    states: HashMap<String, DampingState>,
+}
+
+#[derive(Clone, Copy)]
+pub struct DampingSettings {
+    pub dead_after: u32,
+    pub sick_after: u32,
+    pub recover_after: u32,
+    pub flap_window: u32,
+    pub flap_threshold: u32,
+}
+
+struct DampingState {
+    cycle: u64,
+    reported: Status,
+    sick_streak: u32,
+    dead_streak: u32,
+    better_streak: u32,
+    history: VecDeque<Status>,
+    is_flapping: bool,
+}
+
+impl Default for DampingSettings {
+    fn default() -> DampingSettings {
+        // Notice: default thresholds report every status change right away, and flap \
+        //   detection is disabled (as per the original behavior)
+        DampingSettings {
+            dead_after: 1,
+            sick_after: 1,
+            recover_after: 1,
+            flap_window: 0,
+            flap_threshold: 0,
+        }
+    }
+}
+
+impl Default for Damping {
+    fn default() -> Damping {
+        Damping::new()
+    }
+}
+
+impl Damping {
+    pub fn new() -> Damping {
+        Damping {
+            cycle: 0,
+            states: HashMap::new(),
+        }
+    }
+
+    pub fn begin_cycle(&mut self) {
+        self.cycle += 1;
+    }
+
+    pub fn end_cycle(&mut self) {
+        // Forget about replicas that were not probed during this cycle (ie. removed from maps)
+        let cycle = self.cycle;
+
+        self.states.retain(|_, state| state.cycle == cycle);
+    }
+
+    pub fn apply(
+        &mut self,
+        settings: &DampingSettings,
+        key: &str,
+        replica: &str,
+        status: Status,
+    ) -> Status {
+        let cycle = self.cycle;
+
+        // First result for replica? (there is no previous status to damp against)
+        let state = self
+            .states
+            .entry(key.to_owned())
+            .or_insert_with(|| DampingState {
+                cycle: cycle,
+                reported: status,
+                sick_streak: 0,
+                dead_streak: 0,
+                better_streak: 0,
+                history: VecDeque::new(),
+                is_flapping: false,
+            });
+
+        state.cycle = cycle;
+
+        // Apply hysteresis (a status change is only reported once it has been observed for a \
+        //   number of consecutive cycles)
+        // Notice: dead observations also count towards the sick streak, as a dead replica is at \
+        //   least sick (this way, a replica going dead is reported as sick once 'sick_after' is \
+        //   reached, and then as dead once 'dead_after' is reached).
+        if severity(status) > severity(state.reported) {
+            state.better_streak = 0;
+            state.sick_streak += 1;
+
+            if let Status::Dead = status {
+                state.dead_streak += 1;
+            } else {
+                state.dead_streak = 0;
+            }
+
+            // Notice: thresholds of zero are handled as thresholds of one, as any observation \
+            //   would otherwise be reported right away (eg. every sick replica as dead)
+            if state.dead_streak >= settings.dead_after.max(1) {
+                state.reported = Status::Dead;
+                state.sick_streak = 0;
+                state.dead_streak = 0;
+            } else if state.sick_streak >= settings.sick_after.max(1)
+                && severity(state.reported) < severity(Status::Sick)
+            {
+                // Notice: the dead streak is kept, so that it can still reach 'dead_after'
+                state.reported = Status::Sick;
+                state.sick_streak = 0;
+            }
+        } else if severity(status) < severity(state.reported) {
+            state.better_streak += 1;
+            state.sick_streak = 0;
+            state.dead_streak = 0;
+
+            if state.better_streak >= settings.recover_after.max(1) {
+                state.reported = status;
+                state.better_streak = 0;
+            }
+        } else {
+            state.sick_streak = 0;
+            state.dead_streak = 0;
+            state.better_streak = 0;
+        }
+
+        // Detect flapping (ie. too many raw status changes over the window)
+        if settings.flap_window > 0 {
+            state.history.push_back(status);
+
+            while state.history.len() > settings.flap_window as usize {
+                state.history.pop_front();
+            }
+
+            let transitions = state
+                .history
+                .iter()
+                .zip(state.history.iter().skip(1))
+                .filter(|(previous, next)| severity(**previous) != severity(**next))
+                .count() as u32;
+
+            let is_flapping = transitions >= settings.flap_threshold.max(1);
+
+            if is_flapping != state.is_flapping {
+                if is_flapping == true {
+                    warn!(
+                        "replica started flapping: {} ({} changes over {} cycles)",
+                        replica,
+                        transitions,
+                        state.history.len()
+                    );
+                } else {
+                    info!("replica stopped flapping: {}", replica);
+                }
+
+                state.is_flapping = is_flapping;
+            }
+        }
+
+        // Report flapping replicas as at least sick (as they cannot be trusted to be healthy)
+        match state.reported {
+            Status::Healthy if state.is_flapping == true => Status::Sick,
+            reported => reported,
+        }
+    }
+}
+
+fn severity(status: Status) -> u8 {
+    match status {
+        Status::Healthy => 0,
+        Status::Sick => 1,
+        Status::Dead => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(dead_after: u32, sick_after: u32) -> DampingSettings {
+        DampingSettings {
+            dead_after: dead_after,
+            sick_after: sick_after,
+            ..DampingSettings::default()
+        }
+    }
+
+    fn run(settings: &DampingSettings, statuses: &[Status]) -> Vec<Status> {
+        let mut damping = Damping::new();
+
+        statuses
+            .iter()
+            .map(|status| {
+                damping.begin_cycle();
+
+                damping.apply(settings, "replica", "tcp://replica:80", *status)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_reports_dead_replica_as_sick_first() {
+        assert_eq!(
+            run(
+                &settings(3, 2),
+                &[
+                    Status::Healthy,
+                    Status::Dead,
+                    Status::Dead,
+                    Status::Dead,
+                    Status::Dead
+                ]
+            ),
+            vec![
+                Status::Healthy,
+                Status::Healthy,
+                Status::Sick,
+                Status::Dead,
+                Status::Dead
+            ]
+        );
+    }
+
+    #[test]
+    fn it_counts_mixed_observations_towards_sick() {
+        assert_eq!(
+            run(
+                &settings(2, 2),
+                &[Status::Healthy, Status::Dead, Status::Sick, Status::Dead]
+            ),
+            vec![Status::Healthy, Status::Healthy, Status::Sick, Status::Sick]
+        );
+    }
+
+    #[test]
+    fn it_recovers_after_threshold() {
+        assert_eq!(
+            run(
+                &DampingSettings {
+                    recover_after: 2,
+                    ..settings(1, 1)
+                },
+                &[Status::Dead, Status::Healthy, Status::Healthy]
+            ),
+            vec![Status::Dead, Status::Dead, Status::Healthy]
+        );
+    }
+
+    #[test]
+    fn it_handles_zero_thresholds_as_one() {
+        assert_eq!(
+            run(
+                &settings(0, 0),
+                &[Status::Healthy, Status::Sick, Status::Dead, Status::Sick]
+            ),
+            vec![Status::Healthy, Status::Sick, Status::Dead, Status::Sick]
+        );
+    }
+}
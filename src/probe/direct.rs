@@ -0,0 +1,130 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use http_req::{
+    chunked::ChunkReader,
+    request::{Method, RequestMessage},
+    response::Response,
+    stream::read_head,
+    uri::Uri,
+};
+
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use super::bind::{connect as bind_connect, Bind};
+use super::engine::Engine;
+use super::tls::connect_host as tls_connect_host;
+
+#[derive(Clone, Copy)]
+pub struct Route<'a> {
+    pub ip: Option<IpAddr>,
+    pub bind: Option<&'a Bind>,
+}
+
+pub fn send(
+    engine: &Engine,
+    uri: &Uri,
+    message: &RequestMessage,
+    method: Method,
+    timeout: Duration,
+    route: Route,
+    response_body: &mut Vec<u8>,
+) -> Result<Response, ()> {
+    // Notice: this sends HTTP requests over our own connection, as the HTTP library does not \
+    //   support connecting to a given address or from a given source, client certificates nor \
+    //   custom certificate verification.
+    let host = uri.host().ok_or(())?;
+
+    // Notice: IPv6 hosts are wrapped in brackets in URIs, which must be removed here
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    // Connect to given IP address? (otherwise, resolve host)
+    let address = match route.ip {
+        Some(ip) => SocketAddr::new(ip, uri.corr_port()),
+        None => engine
+            .resolve(host, uri.corr_port())
+            .or(Err(()))?
+            .into_iter()
+            .next()
+            .ok_or(())?,
+    };
+
+    let stream = bind_connect(route.bind, &address, timeout).map_err(|err| {
+        debug!("could not connect to: {} (error: {})", address, err);
+    })?;
+
+    stream.set_read_timeout(Some(timeout)).or(Err(()))?;
+    stream.set_write_timeout(Some(timeout)).or(Err(()))?;
+
+    if uri.scheme() == "https" {
+        exchange(
+            host,
+            tls_connect_host(engine, host, stream)?,
+            message,
+            method,
+            response_body,
+        )
+    } else {
+        exchange(host, stream, message, method, response_body)
+    }
+}
+
+fn exchange<S: Read + Write>(
+    host: &str,
+    mut stream: S,
+    message: &RequestMessage,
+    method: Method,
+    response_body: &mut Vec<u8>,
+) -> Result<Response, ()> {
+    stream
+        .write_all(&message.parse())
+        .and_then(|_| stream.flush())
+        .map_err(|err| {
+            debug!("could not send http request to: {} (error: {})", host, err);
+        })?;
+
+    // Read response head, then body (if any)
+    let mut reader = BufReader::new(stream);
+
+    let response = Response::from_head(&read_head(&mut reader)).map_err(|err| {
+        debug!("invalid http response from: {} (error: {})", host, err);
+    })?;
+
+    let [transfer, content] = response.basic_info(&method);
+
+    if content == "non-empty" {
+        let result = if transfer == "chunked" {
+            ChunkReader::from(reader).read_to_end(response_body)
+        } else if let Some(content_length) = response.content_len() {
+            reader
+                .take(content_length as u64)
+                .read_to_end(response_body)
+        } else {
+            reader.read_to_end(response_body)
+        };
+
+        // Notice: servers commonly close the connection without sending a TLS close \
+        //   notification, which is harmless here as the response is complete.
+        result
+            .or_else(|err| {
+                if err.kind() == ErrorKind::UnexpectedEof {
+                    Ok(0)
+                } else {
+                    Err(err)
+                }
+            })
+            .map_err(|err| {
+                debug!(
+                    "could not read http response body from: {} (error: {})",
+                    host, err
+                );
+            })?;
+    }
+
+    Ok(response)
+}
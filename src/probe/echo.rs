@@ -0,0 +1,199 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use super::bind::Bind;
+
+const ECHO_TYPE_REQUEST_V4: u8 = 8;
+const ECHO_TYPE_REPLY_V4: u8 = 0;
+const ECHO_TYPE_REQUEST_V6: u8 = 128;
+const ECHO_TYPE_REPLY_V6: u8 = 129;
+const ECHO_PAYLOAD: &[u8] = b"crisp-status-local";
+const ECHO_BUFFER_SIZE: usize = 1024;
+
+static ECHO_IDENTIFIER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EchoKind {
+    Raw,
+    Datagram,
+}
+
+pub struct EchoSocket {
+    socket: Socket,
+    kind: EchoKind,
+    address: IpAddr,
+    identifier: u16,
+}
+
+impl EchoSocket {
+    pub fn open(address: IpAddr, kind: EchoKind, bind: Option<&Bind>) -> io::Result<EchoSocket> {
+        let target = SocketAddr::new(address, 0);
+
+        let (domain, protocol) = match address {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+
+        let socket = Socket::new(domain, kind.socket_type(), Some(protocol))?;
+
+        if let Some(bind) = bind {
+            bind.apply(&socket, &target)?;
+        }
+
+        // Notice: connecting the socket filters out ICMP packets received from other hosts
+        socket.connect(&target.into())?;
+
+        // Notice: the identifier is unique to each socket, as multiple replicas may be pinged at \
+        //   the same time, and raw sockets receive all ICMP packets.
+        let identifier = (process::id() as usize)
+            .wrapping_add(ECHO_IDENTIFIER_COUNTER.fetch_add(1, Ordering::Relaxed))
+            as u16;
+
+        Ok(EchoSocket {
+            socket: socket,
+            kind: kind,
+            address: address,
+            identifier: identifier,
+        })
+    }
+
+    pub fn ping(&mut self, sequence: u16, timeout: Duration) -> io::Result<Option<Duration>> {
+        let start_time = Instant::now();
+
+        self.socket.send(&self.request(sequence))?;
+
+        let mut buffer = [0; ECHO_BUFFER_SIZE];
+
+        // Wait for the matching echo reply (ignoring other ICMP packets)
+        loop {
+            let remaining = match timeout.checked_sub(start_time.elapsed()) {
+                Some(remaining) if remaining > Duration::ZERO => remaining,
+                _ => return Ok(None),
+            };
+
+            self.socket.set_read_timeout(Some(remaining))?;
+
+            let size = match self.socket.read(&mut buffer) {
+                Ok(size) => size,
+                Err(ref err)
+                    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(err) => return Err(err),
+            };
+
+            if self.is_reply(&buffer[..size], sequence) == true {
+                return Ok(Some(start_time.elapsed()));
+            }
+        }
+    }
+
+    fn request(&self, sequence: u16) -> Vec<u8> {
+        let echo_type = match self.address {
+            IpAddr::V4(_) => ECHO_TYPE_REQUEST_V4,
+            IpAddr::V6(_) => ECHO_TYPE_REQUEST_V6,
+        };
+
+        let mut packet = vec![echo_type, 0, 0, 0];
+
+        packet.extend_from_slice(&self.identifier.to_be_bytes());
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(ECHO_PAYLOAD);
+
+        // Notice: the ICMPv6 checksum is computed by the kernel, as it covers the IPv6 \
+        //   pseudo-header, which is not known here.
+        if self.address.is_ipv4() == true {
+            let checksum = checksum(&packet);
+
+            packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+
+        packet
+    }
+
+    fn is_reply(&self, packet: &[u8], sequence: u16) -> bool {
+        // Notice: raw ICMPv4 sockets receive packets with their IPv4 header, which is skipped \
+        //   here (its length is given in 32-bit words).
+        let (packet, reply_type) = match self.address {
+            IpAddr::V4(_) if self.kind == EchoKind::Raw => {
+                let header_size = packet.first().map(|byte| (byte & 0x0f) as usize * 4);
+
+                match header_size {
+                    Some(header_size) if packet.len() >= header_size => {
+                        (&packet[header_size..], ECHO_TYPE_REPLY_V4)
+                    }
+                    _ => return false,
+                }
+            }
+            IpAddr::V4(_) => (packet, ECHO_TYPE_REPLY_V4),
+            IpAddr::V6(_) => (packet, ECHO_TYPE_REPLY_V6),
+        };
+
+        // Notice: the identifier of datagram sockets is set by the kernel (which only forwards \
+        //   replies with this identifier to the socket), so it cannot be checked here.
+        packet.len() >= 8
+            && packet[0] == reply_type
+            && (self.kind == EchoKind::Datagram || packet[4..6] == self.identifier.to_be_bytes())
+            && packet[6..8] == sequence.to_be_bytes()
+    }
+}
+
+impl EchoKind {
+    fn socket_type(&self) -> Type {
+        match self {
+            EchoKind::Raw => Type::RAW,
+            EchoKind::Datagram => Type::DGRAM,
+        }
+    }
+}
+
+pub fn detect() -> Option<EchoKind> {
+    // Notice: raw sockets require the 'CAP_NET_RAW' capability, while unprivileged datagram \
+    //   sockets require the process group to be in the 'net.ipv4.ping_group_range' range; \
+    //   both are checked by the kernel upon opening a socket.
+    for kind in &[EchoKind::Raw, EchoKind::Datagram] {
+        match Socket::new(Domain::IPV4, kind.socket_type(), Some(Protocol::ICMPV4)) {
+            Ok(_) => {
+                info!("icmp probes will use {:?} sockets", kind);
+
+                return Some(*kind);
+            }
+            Err(err) => debug!("cannot open icmp {:?} socket (error: {})", kind, err),
+        }
+    }
+
+    warn!(
+        "icmp probes are unsupported, as no icmp socket could be opened (requires the \
+            CAP_NET_RAW capability, or net.ipv4.ping_group_range: {} to include the process group)",
+        fs::read_to_string("/proc/sys/net/ipv4/ping_group_range")
+            .map(|range| range.split_whitespace().collect::<Vec<&str>>().join("-"))
+            .unwrap_or_else(|_| "unknown".to_string())
+    );
+
+    None
+}
+
+fn checksum(packet: &[u8]) -> u16 {
+    let mut sum = packet.chunks(2).fold(0u32, |sum, chunk| {
+        sum + u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]))
+    });
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
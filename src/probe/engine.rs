@@ -0,0 +1,368 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+#[cfg(feature = "probe-http")]
+use rustls::ClientConfig;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "probe-net")]
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+#[cfg(feature = "probe-http")]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::address::AddressFamily;
+#[cfg(feature = "probe-connect")]
+use super::address::AddressPolicy;
+use super::bind::Bind;
+use super::damping::DampingSettings;
+#[cfg(feature = "probe-icmp")]
+use super::echo::{detect as echo_detect, EchoKind};
+use super::metrics::{Metrics, MetricsSnapshot};
+#[cfg(feature = "probe-net")]
+use super::prober::ProbeOutcome;
+#[cfg(feature = "probe-net")]
+use super::resolver::Resolver;
+use super::resolver::ResolverSettings;
+
+// Notice: engine fields are only built in if a probe reading them is built as well, though \
+//   all builder options are accepted with any set of built probes (ie. unused options are \
+//   ignored), so that a same configuration can be used with any build.
+pub struct Engine {
+    #[cfg(feature = "probe-net")]
+    pub(crate) bind: Option<Bind>,
+    pub(crate) damping: DampingSettings,
+    #[cfg(feature = "probe-exec")]
+    pub(crate) exec_allow: Vec<Vec<String>>,
+    #[cfg(feature = "probe-net")]
+    pub(crate) family: AddressFamily,
+    #[cfg(feature = "probe-icmp")]
+    pub(crate) icmp: Option<EchoKind>,
+    pub(crate) metrics: Metrics,
+    #[cfg(feature = "probe-net")]
+    pub(crate) resolver: Resolver,
+    #[cfg(feature = "probe-http")]
+    pub(crate) secrets: HashMap<String, EngineSecret>,
+    #[cfg(feature = "probe-http")]
+    pub(crate) tls: Option<EngineTLS>,
+    #[cfg(feature = "probe-http")]
+    pub(crate) tls_host_configs: Mutex<HashMap<String, Arc<ClientConfig>>>,
+}
+
+pub struct EngineBuilder {
+    bind: Option<Bind>,
+    damping: DampingSettings,
+    exec_allow: Vec<Vec<String>>,
+    family: AddressFamily,
+    resolver: ResolverSettings,
+    secrets: HashMap<String, EngineSecret>,
+    tls: Option<EngineTLS>,
+}
+
+#[cfg_attr(not(feature = "probe-http"), allow(dead_code))]
+pub struct EngineSecret {
+    pub(crate) path: PathBuf,
+    pub(crate) hosts: Vec<String>,
+}
+
+#[derive(Default)]
+#[cfg_attr(not(feature = "probe-http"), allow(dead_code))]
+pub struct EngineTLS {
+    pub(crate) ca_bundles: Vec<PathBuf>,
+    pub(crate) client: Option<(PathBuf, PathBuf)>,
+    pub(crate) clients: Vec<EngineTLSClient>,
+    pub(crate) insecure_skip_verify: Vec<String>,
+}
+
+#[cfg_attr(not(feature = "probe-http"), allow(dead_code))]
+pub struct EngineTLSClient {
+    pub(crate) hosts: Vec<String>,
+    pub(crate) cert: PathBuf,
+    pub(crate) key: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EngineError {
+    InvalidAddressPolicy,
+    InvalidAddressFamily,
+    InvalidBind,
+    InvalidReplica,
+}
+
+impl Engine {
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder {
+            bind: None,
+            damping: DampingSettings::default(),
+            exec_allow: Vec::new(),
+            family: AddressFamily::Any,
+            resolver: ResolverSettings::default(),
+            secrets: HashMap::new(),
+            tls: None,
+        }
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    pub fn count_crash(&self) {
+        // Notice: crashes are counted by the supervisor of the probe thread, as the probe \
+        //   thread cannot account for its own crashes.
+        self.metrics.count_crash();
+    }
+
+    #[cfg(feature = "probe-net")]
+    pub(crate) fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self.resolver.resolve(&self.metrics, host, port)
+    }
+
+    #[cfg(feature = "probe-net")]
+    pub(crate) fn bind_for<'a>(&'a self, bind: &'a Option<Bind>) -> Option<&'a Bind> {
+        // Notice: a bind set on the replica takes precedence over the engine bind
+        bind.as_ref().or(self.bind.as_ref())
+    }
+
+    #[cfg(feature = "probe-net")]
+    pub(crate) fn filter_for_bind(
+        &self,
+        addresses: Vec<SocketAddr>,
+        bind: Option<&Bind>,
+    ) -> Result<Vec<SocketAddr>, ProbeOutcome> {
+        // Only keep addresses that can be reached from the bind address (ie. of the same \
+        //   family), as connecting to the other ones would always fail
+        let bind = match bind {
+            Some(bind) => bind,
+            None => return Ok(addresses),
+        };
+
+        let resolved = addresses.is_empty() == false;
+        let addresses: Vec<SocketAddr> = addresses
+            .into_iter()
+            .filter(|address| bind.accepts(address))
+            .collect();
+
+        if resolved == true && addresses.is_empty() == true {
+            Err(ProbeOutcome::dead(
+                "no address resolved in the family of the bind address",
+            ))
+        } else {
+            Ok(addresses)
+        }
+    }
+
+    #[cfg(feature = "probe-connect")]
+    pub(crate) fn probe_addresses<F>(
+        &self,
+        host: &str,
+        port: u16,
+        family: Option<AddressFamily>,
+        policy: AddressPolicy,
+        bind: Option<&Bind>,
+        probe_address: F,
+    ) -> ProbeOutcome
+    where
+        F: Fn(SocketAddr) -> ProbeOutcome + Sync,
+    {
+        // Notice: this is the resolve path shared by all probes connecting to a host, so that \
+        //   they all honour the address family (set on the replica, or on the engine) and the \
+        //   address policy the same way.
+        let addresses = match self.resolve(host, port) {
+            Ok(addresses) => addresses,
+            Err(err) => return ProbeOutcome::unresolved(err),
+        };
+
+        let addresses = match self.filter_for_bind(addresses, bind) {
+            Ok(addresses) => addresses,
+            Err(outcome) => return outcome,
+        };
+
+        family.unwrap_or(self.family).probe(addresses, |addresses| {
+            policy.probe(addresses, &probe_address)
+        })
+    }
+}
+
+impl EngineBuilder {
+    pub fn bind(mut self, bind: Bind) -> EngineBuilder {
+        self.bind = Some(bind);
+        self
+    }
+
+    pub fn dead_after(mut self, cycles: u32) -> EngineBuilder {
+        self.damping.dead_after = cycles;
+        self
+    }
+
+    pub fn sick_after(mut self, cycles: u32) -> EngineBuilder {
+        self.damping.sick_after = cycles;
+        self
+    }
+
+    pub fn recover_after(mut self, cycles: u32) -> EngineBuilder {
+        self.damping.recover_after = cycles;
+        self
+    }
+
+    pub fn flap_detection(mut self, window: u32, threshold: u32) -> EngineBuilder {
+        self.damping.flap_window = window;
+        self.damping.flap_threshold = threshold;
+        self
+    }
+
+    pub fn exec_allow<C: AsRef<str>>(mut self, command_line: C) -> EngineBuilder {
+        // Notice: allowed command lines hold the command, followed by its arguments (separated \
+        //   by whitespace)
+        self.exec_allow.push(
+            command_line
+                .as_ref()
+                .split_whitespace()
+                .map(|part| part.to_owned())
+                .collect(),
+        );
+        self
+    }
+
+    pub fn family(mut self, family: AddressFamily) -> EngineBuilder {
+        self.family = family;
+        self
+    }
+
+    pub fn resolver_upstream<A: Into<SocketAddr>>(mut self, address: A) -> EngineBuilder {
+        self.resolver.upstreams.push(address.into());
+        self
+    }
+
+    pub fn resolver_timeout(mut self, timeout: Duration) -> EngineBuilder {
+        self.resolver.timeout = timeout;
+        self
+    }
+
+    pub fn resolver_system_ttl(mut self, ttl: Duration) -> EngineBuilder {
+        self.resolver.system_ttl = ttl;
+        self
+    }
+
+    pub fn resolver_maximum_ttl(mut self, ttl: Duration) -> EngineBuilder {
+        self.resolver.maximum_ttl = ttl;
+        self
+    }
+
+    pub fn resolver_stale_ttl(mut self, ttl: Duration) -> EngineBuilder {
+        self.resolver.stale_ttl = ttl;
+        self
+    }
+
+    pub fn secret<N: Into<String>, P: Into<PathBuf>>(
+        mut self,
+        name: N,
+        path: P,
+        hosts: Vec<String>,
+    ) -> EngineBuilder {
+        self.secrets.insert(
+            name.into(),
+            EngineSecret {
+                path: path.into(),
+                hosts: hosts,
+            },
+        );
+        self
+    }
+
+    pub fn tls(mut self) -> EngineBuilder {
+        self.tls_mut();
+        self
+    }
+
+    pub fn tls_ca_bundle<P: Into<PathBuf>>(mut self, path: P) -> EngineBuilder {
+        self.tls_mut().ca_bundles.push(path.into());
+        self
+    }
+
+    pub fn tls_client<P: Into<PathBuf>>(mut self, cert: P, key: P) -> EngineBuilder {
+        self.tls_mut().client = Some((cert.into(), key.into()));
+        self
+    }
+
+    pub fn tls_host_client<P: Into<PathBuf>>(
+        mut self,
+        hosts: Vec<String>,
+        cert: P,
+        key: P,
+    ) -> EngineBuilder {
+        self.tls_mut().clients.push(EngineTLSClient {
+            hosts: hosts,
+            cert: cert.into(),
+            key: key.into(),
+        });
+        self
+    }
+
+    pub fn tls_insecure_skip_verify<H: Into<String>>(mut self, host: H) -> EngineBuilder {
+        self.tls_mut().insecure_skip_verify.push(host.into());
+        self
+    }
+
+    pub fn build(self) -> Engine {
+        // Notice: options that are only read by probes which are not built in are ignored
+        #[cfg(not(feature = "probe-net"))]
+        let _ = (&self.bind, &self.family, &self.resolver);
+        #[cfg(not(feature = "probe-exec"))]
+        let _ = &self.exec_allow;
+        #[cfg(not(feature = "probe-http"))]
+        let _ = (&self.secrets, &self.tls);
+
+        Engine {
+            #[cfg(feature = "probe-net")]
+            bind: self.bind.clone(),
+            damping: self.damping,
+            #[cfg(feature = "probe-exec")]
+            exec_allow: self.exec_allow,
+            #[cfg(feature = "probe-net")]
+            family: self.family,
+            #[cfg(feature = "probe-icmp")]
+            icmp: echo_detect(),
+            metrics: Metrics::default(),
+            #[cfg(feature = "probe-net")]
+            resolver: Resolver::new(ResolverSettings {
+                bind: self.bind,
+                ..self.resolver
+            }),
+            #[cfg(feature = "probe-http")]
+            secrets: self.secrets,
+            #[cfg(feature = "probe-http")]
+            tls: self.tls,
+            #[cfg(feature = "probe-http")]
+            tls_host_configs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn tls_mut(&mut self) -> &mut EngineTLS {
+        // Notice: a local TLS configuration gets enabled as soon as any TLS option is set
+        self.tls.get_or_insert_with(EngineTLS::default)
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, format: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            format,
+            "{}",
+            match self {
+                EngineError::InvalidAddressPolicy => "invalid address policy",
+                EngineError::InvalidAddressFamily => "invalid address family",
+                EngineError::InvalidBind => "invalid bind address or interface",
+                EngineError::InvalidReplica => "invalid replica url",
+            }
+        )
+    }
+}
+
+impl Error for EngineError {}
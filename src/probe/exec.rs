@@ -0,0 +1,296 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use url::Url;
+
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::engine::Engine;
+use super::map::{MapMetrics, MapServiceNode};
+use super::prober::{acquire_dead_timeout, ProbeOutcome, Prober};
+
+const EXEC_WAIT_POLL_MILLISECONDS: u64 = 50;
+const EXEC_OUTPUT_MAXIMUM_SIZE: u64 = 4096;
+const EXEC_OUTPUT_WAIT_MILLISECONDS: u64 = 500;
+
+#[derive(Debug)]
+pub struct ExecProber {
+    command: String,
+    arguments: Vec<String>,
+}
+
+impl Prober for ExecProber {
+    fn parse(url: &Url) -> Result<ExecProber, ()> {
+        // Notice: the command to execute is passed as the URL path, and its arguments are passed \
+        //   in order using repeated 'arg' query parameters (eg. \
+        //   'exec:///usr/lib/nagios/plugins/check_tcp?arg=-H&arg=license.local'). Commands must \
+        //   be absolute paths, as they are checked against the local allow-list upon execution.
+        let command = url.path().to_owned();
+
+        if url.host().is_some() || command.starts_with('/') == false {
+            return Err(());
+        }
+
+        let arguments = url
+            .query_pairs()
+            .filter(|(key, _)| key == "arg")
+            .map(|(_, value)| value.into_owned())
+            .collect();
+
+        Ok(ExecProber {
+            command: command,
+            arguments: arguments,
+        })
+    }
+
+    fn probe(
+        &self,
+        engine: &Engine,
+        _node: &MapServiceNode,
+        metrics: &Option<MapMetrics>,
+    ) -> ProbeOutcome {
+        let command = self.command.as_str();
+
+        // Notice: as the probe map is pulled from a remote server, only command lines that are \
+        //   explicitly allowed in the local configuration can be executed. Arguments are part \
+        //   of the allowed command line, as an allowed interpreter (or any multi-purpose tool) \
+        //   could otherwise be made to run anything.
+        let is_allowed = engine.exec_allow.iter().any(|allowed| {
+            allowed.first().map(|part| part.as_str()) == Some(command)
+                && allowed[1..] == self.arguments[..]
+        });
+
+        if is_allowed == false {
+            error!(
+                "prober poll refused to run exec command: {} {:?} (not in the exec allow-list)",
+                command, self.arguments
+            );
+
+            return ProbeOutcome::dead("command not allowed");
+        }
+
+        debug!("prober poll will fire for exec command: {}", command);
+
+        // Map exit code using Nagios plugin conventions (ie. OK, WARNING, CRITICAL, UNKNOWN)
+        match run(command, &self.arguments, acquire_dead_timeout(metrics)) {
+            Some(0) => ProbeOutcome::healthy(),
+            Some(1) => ProbeOutcome::sick("command exited with warning code"),
+            Some(code) => ProbeOutcome::dead(format!("command exited with code: {}", code)),
+            None => ProbeOutcome::dead("command failed or timed out"),
+        }
+    }
+}
+
+fn run(command: &str, arguments: &[String], timeout: Duration) -> Option<i32> {
+    // Notice: the command is spawned in its own process group, so that the whole group can be \
+    //   killed upon timeout (this prevents leaving orphan sub-processes behind, eg. when the \
+    //   command is a shell script that spawns other commands).
+    let mut child = match Command::new(command)
+        .args(arguments)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            warn!("could not spawn exec command: {} (error: {})", command, err);
+
+            return None;
+        }
+    };
+
+    // Drain output in the background (as a filled pipe would otherwise block the command)
+    let (output_sender, output_receiver) = mpsc::channel();
+
+    if let Some(mut stdout) = child.stdout.take() {
+        thread::spawn(move || {
+            let mut output = Vec::new();
+
+            stdout
+                .by_ref()
+                .take(EXEC_OUTPUT_MAXIMUM_SIZE)
+                .read_to_end(&mut output)
+                .ok();
+
+            output_sender.send(output).ok();
+
+            // Discard any remaining output
+            io::copy(&mut stdout, &mut io::sink()).ok();
+        });
+    }
+
+    let deadline = Instant::now() + timeout;
+
+    // Notice: the command process is only waited for without being reaped, as its process \
+    //   group identifier could otherwise be reused by another process group (that would then \
+    //   get killed below).
+    let timed_out = loop {
+        match has_exited(child.id()) {
+            Ok(true) => break false,
+            Ok(false) => {
+                if Instant::now() >= deadline {
+                    warn!(
+                        "exec command timed out: {}, killing its process group",
+                        command
+                    );
+
+                    break true;
+                }
+
+                thread::sleep(Duration::from_millis(EXEC_WAIT_POLL_MILLISECONDS));
+            }
+            Err(err) => {
+                warn!(
+                    "could not wait for exec command: {} (error: {})",
+                    command, err
+                );
+
+                break true;
+            }
+        }
+    };
+
+    // Kill the whole process group, including any sub-process left running (even if the \
+    //   command exited by itself), as those would otherwise hold the output pipe open forever; \
+    //   then reap the command process.
+    kill_group(child.id());
+
+    let exit_code = match child.wait() {
+        Ok(status) if timed_out == false => status.code(),
+        _ => None,
+    };
+
+    // Notice: the output is not awaited forever, as sub-processes left running in the \
+    //   background may hold the output pipe open.
+    let output = output_receiver
+        .recv_timeout(Duration::from_millis(EXEC_OUTPUT_WAIT_MILLISECONDS))
+        .unwrap_or_default();
+
+    debug!(
+        "exec command: {} exited with code: {:?} and output: {}",
+        command,
+        exit_code,
+        String::from_utf8_lossy(&output)
+            .lines()
+            .next()
+            .unwrap_or("")
+    );
+
+    exit_code
+}
+
+fn has_exited(process_id: u32) -> io::Result<bool> {
+    // Notice: the signal number is only set if the process exited (as the structure is zeroed \
+    //   beforehand, and left untouched otherwise).
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+
+    let result = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            process_id as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        )
+    };
+
+    if result == 0 {
+        Ok(info.si_signo != 0)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn kill_group(process_id: u32) {
+    // Notice: the process group identifier is the command process identifier, as the command \
+    //   is the leader of its own group; it must only be called while the command process is \
+    //   not reaped yet, as the group identifier stays reserved until then.
+    unsafe {
+        libc::kill(-(process_id as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::map::MapMetricsLocal;
+    use super::super::status::Status;
+
+    fn node() -> MapServiceNode {
+        serde_json::from_str(r#"{"id": "node", "mode": "local"}"#).unwrap()
+    }
+
+    fn probe(engine: &Engine, url: &str, delay_dead: u64) -> ProbeOutcome {
+        let metrics = Some(MapMetrics {
+            local: MapMetricsLocal {
+                retry: 0,
+                delay_dead: delay_dead,
+                delay_sick: delay_dead,
+            },
+        });
+
+        ExecProber::parse(&Url::parse(url).unwrap())
+            .unwrap()
+            .probe(engine, &node(), &metrics)
+    }
+
+    #[test]
+    fn it_maps_exit_codes() {
+        let engine = Engine::builder()
+            .exec_allow("/bin/true")
+            .exec_allow("/bin/false")
+            .build();
+
+        assert_eq!(
+            probe(&engine, "exec:///bin/true", 5).status,
+            Status::Healthy
+        );
+        assert_eq!(probe(&engine, "exec:///bin/false", 5).status, Status::Sick);
+
+        assert_eq!(
+            run(
+                "/bin/sh",
+                &["-c".to_string(), "exit 2".to_string()],
+                Duration::from_secs(5)
+            ),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn it_kills_commands_on_timeout() {
+        let engine = Engine::builder().exec_allow("/bin/sleep 10").build();
+        let start_time = Instant::now();
+
+        let outcome = probe(&engine, "exec:///bin/sleep?arg=10", 1);
+
+        assert_eq!(outcome.status, Status::Dead);
+        assert_eq!(
+            outcome.reason,
+            Some("command failed or timed out".to_string())
+        );
+        assert!(start_time.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn it_refuses_commands_not_allowed() {
+        let engine = Engine::builder().exec_allow("/bin/true").build();
+
+        for url in &["exec:///bin/false", "exec:///bin/true?arg=--version"] {
+            let outcome = probe(&engine, url, 5);
+
+            assert_eq!(outcome.status, Status::Dead);
+            assert_eq!(outcome.reason, Some("command not allowed".to_string()));
+        }
+    }
+}
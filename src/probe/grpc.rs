@@ -0,0 +1,625 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use hpack::{Decoder, Encoder};
+use url::Url;
+
+use std::io::{Read, Write};
+
+use super::address::{AddressFamily, AddressPolicy};
+use super::bind::{connect as bind_connect, Bind};
+use super::engine::Engine;
+use super::map::{MapMetrics, MapServiceNode};
+use super::prober::{
+    acquire_dead_timeout, host_string, options as prober_options, ProbeOutcome, Prober,
+};
+use super::tls::connect_http2 as tls_connect_http2;
+
+const GRPC_HTTP2_PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const GRPC_HTTP2_STREAM_ID: u32 = 1;
+const GRPC_HTTP2_FRAME_MAXIMUM_SIZE: usize = 16384;
+const GRPC_HTTP2_FRAMES_MAXIMUM: usize = 128;
+const GRPC_HEALTH_CHECK_PATH: &'static str = "/grpc.health.v1.Health/Check";
+
+const FRAME_TYPE_DATA: u8 = 0x0;
+const FRAME_TYPE_HEADERS: u8 = 0x1;
+const FRAME_TYPE_RST_STREAM: u8 = 0x3;
+const FRAME_TYPE_SETTINGS: u8 = 0x4;
+const FRAME_TYPE_PING: u8 = 0x6;
+const FRAME_TYPE_GOAWAY: u8 = 0x7;
+const FRAME_TYPE_CONTINUATION: u8 = 0x9;
+
+const FRAME_FLAG_END_STREAM: u8 = 0x1;
+const FRAME_FLAG_ACK: u8 = 0x1;
+const FRAME_FLAG_END_HEADERS: u8 = 0x4;
+const FRAME_FLAG_PADDED: u8 = 0x8;
+const FRAME_FLAG_PRIORITY: u8 = 0x20;
+
+lazy_static! {
+    static ref GRPC_HTTP_HEADER_USERAGENT: String =
+        format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+}
+
+#[derive(Debug)]
+pub struct GRPCProber {
+    host: String,
+    port: u16,
+    service: String,
+    secure: bool,
+    addresses: AddressPolicy,
+    family: Option<AddressFamily>,
+    bind: Option<Bind>,
+}
+
+#[derive(Debug)]
+pub enum GRPCServingStatus {
+    Unknown,
+    Serving,
+    NotServing,
+    ServiceUnknown,
+}
+
+struct GRPCFrame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct GRPCResponse {
+    http_status: Option<String>,
+    grpc_status: Option<String>,
+    grpc_message: Option<String>,
+    message: Vec<u8>,
+}
+
+impl Prober for GRPCProber {
+    fn parse(url: &Url) -> Result<GRPCProber, ()> {
+        // Notice: the service name to check health for is passed as the URL path (eg. \
+        //   'grpc://api.local:50051/my.package.Service'); an empty path checks for the overall \
+        //   server health, as per the gRPC health checking protocol.
+        let options = prober_options(url);
+
+        match (url.host(), url.port()) {
+            (Some(host), Some(port)) => Ok(GRPCProber {
+                host: host_string(host),
+                port: port,
+                service: url.path().trim_start_matches('/').to_owned(),
+                secure: url.scheme() == "grpcs",
+                addresses: AddressPolicy::from_options(&options)?,
+                family: AddressFamily::from_options(&options)?,
+                bind: Bind::from_options(&options)?,
+            }),
+            _ => Err(()),
+        }
+    }
+
+    fn probe(
+        &self,
+        engine: &Engine,
+        _node: &MapServiceNode,
+        metrics: &Option<MapMetrics>,
+    ) -> ProbeOutcome {
+        let (host, port) = (self.host.as_str(), self.port);
+        let dead_timeout = acquire_dead_timeout(metrics);
+        let bind = engine.bind_for(&self.bind);
+
+        engine.probe_addresses(host, port, self.family, self.addresses, bind, |address| {
+            debug!("prober poll will fire for grpc target: {}", address);
+
+            let stream = match bind_connect(bind, &address, dead_timeout) {
+                Ok(stream) => stream,
+                Err(err) => return ProbeOutcome::dead(format!("connection failed: {}", err)),
+            };
+
+            if stream.set_read_timeout(Some(dead_timeout)).is_err()
+                || stream.set_write_timeout(Some(dead_timeout)).is_err()
+            {
+                return ProbeOutcome::dead("could not set socket timeouts");
+            }
+
+            // Notice: IPv6 hosts must be wrapped in brackets in the HTTP/2 authority
+            let authority = if host.contains(':') == true {
+                format!("[{}]:{}", host, port)
+            } else {
+                format!("{}:{}", host, port)
+            };
+
+            let serving_status = if self.secure == true {
+                tls_connect_http2(host, stream)
+                    .and_then(|stream| check(stream, &authority, &self.service, true))
+            } else {
+                check(stream, &authority, &self.service, false)
+            };
+
+            debug!(
+                "got prober poll result for grpc target: {} with serving status: {:?}",
+                address, serving_status
+            );
+
+            // Consider only serving services as up (this excludes the unknown status)
+            match serving_status {
+                Ok(GRPCServingStatus::Serving) => ProbeOutcome::healthy(),
+                Ok(serving_status) => {
+                    ProbeOutcome::dead(format!("serving status: {:?}", serving_status))
+                }
+                Err(_) => ProbeOutcome::dead("health check failed"),
+            }
+        })
+    }
+}
+
+fn check<S: Read + Write>(
+    mut stream: S,
+    authority: &str,
+    service: &str,
+    secure: bool,
+) -> Result<GRPCServingStatus, ()> {
+    // Open HTTP/2 connection (with an empty set of settings, ie. protocol defaults)
+    stream.write_all(GRPC_HTTP2_PREFACE).or(Err(()))?;
+
+    write_frame(&mut stream, FRAME_TYPE_SETTINGS, 0, 0, &[])?;
+
+    // Send health check request (as a single stream, ended by the request message)
+    let headers_block = Encoder::new().encode(vec![
+        (&b":method"[..], &b"POST"[..]),
+        (
+            &b":scheme"[..],
+            if secure == true {
+                &b"https"[..]
+            } else {
+                &b"http"[..]
+            },
+        ),
+        (&b":path"[..], GRPC_HEALTH_CHECK_PATH.as_bytes()),
+        (&b":authority"[..], authority.as_bytes()),
+        (&b"content-type"[..], &b"application/grpc"[..]),
+        (&b"te"[..], &b"trailers"[..]),
+        (&b"user-agent"[..], GRPC_HTTP_HEADER_USERAGENT.as_bytes()),
+    ]);
+
+    write_frame(
+        &mut stream,
+        FRAME_TYPE_HEADERS,
+        FRAME_FLAG_END_HEADERS,
+        GRPC_HTTP2_STREAM_ID,
+        &headers_block,
+    )?;
+
+    write_frame(
+        &mut stream,
+        FRAME_TYPE_DATA,
+        FRAME_FLAG_END_STREAM,
+        GRPC_HTTP2_STREAM_ID,
+        &encode_request_message(service),
+    )?;
+
+    // Read response (headers, message and trailers)
+    let response = read_response(&mut stream)?;
+
+    debug!(
+        "got grpc health check response for: {} with http status: {:?}, grpc status: {:?} \
+            and grpc message: {:?}",
+        authority, response.http_status, response.grpc_status, response.grpc_message
+    );
+
+    // Notice: the gRPC status is usually sent in trailers, though it can also be sent in \
+    //   headers in the case of a 'Trailers-Only' response (which is used for errors).
+    if response.http_status.as_deref() != Some("200")
+        || response.grpc_status.as_deref() != Some("0")
+    {
+        return Err(());
+    }
+
+    decode_response_message(&response.message)
+}
+
+fn write_frame<S: Read + Write>(
+    stream: &mut S,
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: &[u8],
+) -> Result<(), ()> {
+    let size = payload.len() as u32;
+
+    let mut frame = Vec::with_capacity(9 + payload.len());
+
+    frame.extend_from_slice(&size.to_be_bytes()[1..]);
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&(stream_id & 0x7fff_ffff).to_be_bytes());
+    frame.extend_from_slice(payload);
+
+    stream
+        .write_all(&frame)
+        .and_then(|_| stream.flush())
+        .or(Err(()))
+}
+
+fn read_frame<S: Read + Write>(stream: &mut S) -> Result<GRPCFrame, ()> {
+    let mut header = [0; 9];
+
+    stream.read_exact(&mut header).or(Err(()))?;
+
+    let size = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+
+    // Notice: as we do not advertise a larger maximum frame size, servers must not send frames \
+    //   larger than the protocol default.
+    if size > GRPC_HTTP2_FRAME_MAXIMUM_SIZE {
+        return Err(());
+    }
+
+    let mut payload = vec![0; size];
+
+    stream.read_exact(&mut payload).or(Err(()))?;
+
+    Ok(GRPCFrame {
+        kind: header[3],
+        flags: header[4],
+        stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+        payload: payload,
+    })
+}
+
+fn read_response<S: Read + Write>(stream: &mut S) -> Result<GRPCResponse, ()> {
+    let mut response = GRPCResponse::default();
+    let mut decoder = Decoder::new();
+    let mut headers_block: Vec<u8> = Vec::new();
+    let mut headers_end_stream = false;
+
+    for _ in 0..GRPC_HTTP2_FRAMES_MAXIMUM {
+        let frame = read_frame(stream)?;
+
+        match frame.kind {
+            FRAME_TYPE_SETTINGS if frame.flags & FRAME_FLAG_ACK == 0 => {
+                // Acknowledge server settings (as required by the protocol)
+                write_frame(stream, FRAME_TYPE_SETTINGS, FRAME_FLAG_ACK, 0, &[])?;
+            }
+            FRAME_TYPE_PING if frame.flags & FRAME_FLAG_ACK == 0 => {
+                write_frame(stream, FRAME_TYPE_PING, FRAME_FLAG_ACK, 0, &frame.payload)?;
+            }
+            FRAME_TYPE_GOAWAY => {
+                debug!("grpc server sent goaway frame");
+
+                return Err(());
+            }
+            FRAME_TYPE_RST_STREAM if frame.stream_id == GRPC_HTTP2_STREAM_ID => {
+                debug!("grpc server reset health check stream");
+
+                return Err(());
+            }
+            FRAME_TYPE_HEADERS | FRAME_TYPE_CONTINUATION
+                if frame.stream_id == GRPC_HTTP2_STREAM_ID =>
+            {
+                let mut fragment = &frame.payload[..];
+
+                if frame.kind == FRAME_TYPE_HEADERS {
+                    headers_end_stream = frame.flags & FRAME_FLAG_END_STREAM != 0;
+
+                    fragment = strip_padding(fragment, frame.flags)?;
+
+                    if frame.flags & FRAME_FLAG_PRIORITY != 0 {
+                        fragment = fragment.get(5..).ok_or(())?;
+                    }
+                }
+
+                headers_block.extend_from_slice(fragment);
+
+                // Header block complete? (it can span over multiple continuation frames)
+                if frame.flags & FRAME_FLAG_END_HEADERS != 0 {
+                    let headers = decoder.decode(&headers_block).or(Err(()))?;
+
+                    headers_block.clear();
+
+                    for (name, value) in headers {
+                        let value = String::from_utf8_lossy(&value).into_owned();
+
+                        match &name[..] {
+                            b":status" => response.http_status = Some(value),
+                            b"grpc-status" => response.grpc_status = Some(value),
+                            b"grpc-message" => response.grpc_message = Some(value),
+                            _ => {}
+                        }
+                    }
+
+                    if headers_end_stream == true {
+                        return Ok(response);
+                    }
+                }
+            }
+            FRAME_TYPE_DATA if frame.stream_id == GRPC_HTTP2_STREAM_ID => {
+                response
+                    .message
+                    .extend_from_slice(strip_padding(&frame.payload, frame.flags)?);
+
+                if frame.flags & FRAME_FLAG_END_STREAM != 0 {
+                    return Ok(response);
+                }
+            }
+            _ => {
+                // Ignore other frames (eg. window updates, or frames for other streams)
+            }
+        }
+    }
+
+    Err(())
+}
+
+fn strip_padding(payload: &[u8], flags: u8) -> Result<&[u8], ()> {
+    if flags & FRAME_FLAG_PADDED != 0 {
+        let padding = *payload.first().ok_or(())? as usize;
+
+        if padding + 1 > payload.len() {
+            return Err(());
+        }
+
+        Ok(&payload[1..(payload.len() - padding)])
+    } else {
+        Ok(payload)
+    }
+}
+
+fn encode_request_message(service: &str) -> Vec<u8> {
+    // Encode 'HealthCheckRequest' protobuf message, which holds a single 'service' string field \
+    //   (tag 1, length-delimited; omitted if empty, as per proto3 defaults).
+    let mut message = Vec::new();
+
+    if service.is_empty() == false {
+        message.push(0x0a);
+
+        encode_varint(&mut message, service.len() as u64);
+
+        message.extend_from_slice(service.as_bytes());
+    }
+
+    // Prefix message with gRPC framing (uncompressed flag, then message length)
+    let mut frame = Vec::with_capacity(5 + message.len());
+
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&message);
+
+    frame
+}
+
+fn decode_response_message(frame: &[u8]) -> Result<GRPCServingStatus, ()> {
+    // Unpack gRPC framing (compressed messages are not supported, as we do not accept any \
+    //   compression algorithm in request headers)
+    if frame.len() < 5 || frame[0] != 0 {
+        return Err(());
+    }
+
+    let size = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]) as usize;
+    let mut message = frame.get(5..(5 + size)).ok_or(())?;
+
+    // Decode 'HealthCheckResponse' protobuf message, which holds a single 'status' enum field \
+    //   (tag 1, varint); unknown fields are skipped.
+    let mut status = 0;
+
+    while message.is_empty() == false {
+        let key = decode_varint(&mut message)?;
+
+        match (key >> 3, key & 0x7) {
+            (1, 0) => status = decode_varint(&mut message)?,
+            (_, 0) => {
+                decode_varint(&mut message)?;
+            }
+            (_, 1) => message = message.get(8..).ok_or(())?,
+            (_, 2) => {
+                let size = decode_varint(&mut message)? as usize;
+
+                message = message.get(size..).ok_or(())?;
+            }
+            (_, 5) => message = message.get(4..).ok_or(())?,
+            _ => return Err(()),
+        }
+    }
+
+    Ok(match status {
+        1 => GRPCServingStatus::Serving,
+        2 => GRPCServingStatus::NotServing,
+        3 => GRPCServingStatus::ServiceUnknown,
+        _ => GRPCServingStatus::Unknown,
+    })
+}
+
+fn encode_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+
+        value >>= 7;
+    }
+
+    buffer.push(value as u8);
+}
+
+fn decode_varint(buffer: &mut &[u8]) -> Result<u64, ()> {
+    let mut value = 0;
+
+    for shift in 0..10 {
+        let byte = *buffer.first().ok_or(())?;
+
+        *buffer = &buffer[1..];
+
+        value |= ((byte & 0x7f) as u64) << (shift * 7);
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    enum Reply {
+        Status(u64),
+        TrailersOnly(&'static str),
+    }
+
+    fn serve(reply: Reply) -> (TcpStream, thread::JoinHandle<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut preface = [0; 24];
+
+            stream.read_exact(&mut preface).unwrap();
+
+            assert_eq!(&preface[..], GRPC_HTTP2_PREFACE);
+
+            write_frame(&mut stream, FRAME_TYPE_SETTINGS, 0, 0, &[]).unwrap();
+
+            // Read request (up to the end of the request stream)
+            let (mut path, mut message) = (String::new(), Vec::new());
+
+            loop {
+                let frame = read_frame(&mut stream).unwrap();
+
+                match frame.kind {
+                    FRAME_TYPE_HEADERS => {
+                        for (name, value) in Decoder::new().decode(&frame.payload).unwrap() {
+                            if name == b":path" {
+                                path = String::from_utf8(value).unwrap();
+                            }
+                        }
+                    }
+                    FRAME_TYPE_DATA => {
+                        message.extend_from_slice(&frame.payload);
+
+                        if frame.flags & FRAME_FLAG_END_STREAM != 0 {
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            let mut encoder = Encoder::new();
+
+            match reply {
+                Reply::Status(status) => {
+                    let headers = encoder.encode(vec![
+                        (&b":status"[..], &b"200"[..]),
+                        (&b"content-type"[..], &b"application/grpc"[..]),
+                    ]);
+
+                    write_frame(
+                        &mut stream,
+                        FRAME_TYPE_HEADERS,
+                        FRAME_FLAG_END_HEADERS,
+                        GRPC_HTTP2_STREAM_ID,
+                        &headers,
+                    )
+                    .unwrap();
+
+                    write_frame(
+                        &mut stream,
+                        FRAME_TYPE_DATA,
+                        0,
+                        GRPC_HTTP2_STREAM_ID,
+                        &[0, 0, 0, 0, 2, 0x08, status as u8],
+                    )
+                    .unwrap();
+
+                    let trailers = encoder.encode(vec![(&b"grpc-status"[..], &b"0"[..])]);
+
+                    write_frame(
+                        &mut stream,
+                        FRAME_TYPE_HEADERS,
+                        FRAME_FLAG_END_HEADERS | FRAME_FLAG_END_STREAM,
+                        GRPC_HTTP2_STREAM_ID,
+                        &trailers,
+                    )
+                    .unwrap();
+                }
+                Reply::TrailersOnly(grpc_status) => {
+                    let headers = encoder.encode(vec![
+                        (&b":status"[..], &b"200"[..]),
+                        (&b"content-type"[..], &b"application/grpc"[..]),
+                        (&b"grpc-status"[..], grpc_status.as_bytes()),
+                        (&b"grpc-message"[..], &b"unknown service"[..]),
+                    ]);
+
+                    write_frame(
+                        &mut stream,
+                        FRAME_TYPE_HEADERS,
+                        FRAME_FLAG_END_HEADERS | FRAME_FLAG_END_STREAM,
+                        GRPC_HTTP2_STREAM_ID,
+                        &headers,
+                    )
+                    .unwrap();
+                }
+            }
+
+            // Notice: wait for the client to close the connection, as closing it first with \
+            //   unread frames (eg. a settings acknowledgement) would reset it, and might discard \
+            //   the reply before the client reads it
+            let _ = stream.read_to_end(&mut Vec::new());
+
+            (path, message)
+        });
+
+        (TcpStream::connect(address).unwrap(), server)
+    }
+
+    #[test]
+    fn it_checks_serving() {
+        let (stream, server) = serve(Reply::Status(1));
+
+        assert!(matches!(
+            check(stream, "localhost", "my.Service", false),
+            Ok(GRPCServingStatus::Serving)
+        ));
+
+        let (path, message) = server.join().unwrap();
+
+        assert_eq!(path, GRPC_HEALTH_CHECK_PATH);
+        assert_eq!(message, encode_request_message("my.Service"));
+    }
+
+    #[test]
+    fn it_checks_not_serving() {
+        let (stream, server) = serve(Reply::Status(2));
+
+        assert!(matches!(
+            check(stream, "localhost", "", false),
+            Ok(GRPCServingStatus::NotServing)
+        ));
+
+        let (_, message) = server.join().unwrap();
+
+        assert_eq!(message, vec![0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn it_fails_on_trailers_only_error() {
+        let (stream, server) = serve(Reply::TrailersOnly("12"));
+
+        assert!(check(stream, "localhost", "my.Service", false).is_err());
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn it_decodes_varints() {
+        let mut buffer = Vec::new();
+
+        encode_varint(&mut buffer, 300);
+
+        assert_eq!(buffer, vec![0xac, 0x02]);
+        assert_eq!(decode_varint(&mut &buffer[..]), Ok(300));
+    }
+}
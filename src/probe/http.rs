@@ -0,0 +1,750 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use http_req::{
+    request::{Method, RedirectPolicy, Request, RequestMessage},
+    response::Response,
+    uri::Uri,
+};
+use url::Url;
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use super::address::{AddressFamily, AddressPolicy};
+use super::bind::Bind;
+use super::body::{check_http_body, has_http_body_checks};
+use super::direct::{send as direct_send, Route as DirectRoute};
+use super::engine::Engine;
+use super::map::{MapMetrics, MapServiceNode, MapServiceNodeHTTP, MapServiceNodeHTTPStatusCodes};
+use super::prober::{
+    acquire_dead_timeout, host_string, options as prober_options, ProbeOutcome, Prober,
+};
+use super::secret::{contains as secret_contains, substitute as secret_substitute};
+
+const HTTP_HEALTHY_ABOVE: u16 = 200;
+const HTTP_HEALTHY_BELOW: u16 = 400;
+const HTTP_REDIRECTS_MAX_HOPS: u8 = 5;
+
+lazy_static! {
+    static ref HTTP_HEADER_USERAGENT: String =
+        format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+}
+
+#[derive(Debug)]
+pub struct HTTPProber {
+    url: String,
+    host: String,
+    port: u16,
+    addresses: AddressPolicy,
+    family: Option<AddressFamily>,
+    bind: Option<Bind>,
+}
+
+struct HTTPRequest {
+    method: Method,
+    headers: Vec<(String, String)>,
+    headers_with_secrets: Vec<String>,
+    body: Option<String>,
+    body_has_secrets: bool,
+}
+
+impl HTTPRequest {
+    fn drop_secrets(&mut self) {
+        let headers_with_secrets = &self.headers_with_secrets;
+
+        self.headers
+            .retain(|(header_name, _)| headers_with_secrets.contains(header_name) == false);
+        self.headers_with_secrets.clear();
+
+        if self.body_has_secrets == true {
+            self.body = None;
+            self.body_has_secrets = false;
+        }
+    }
+}
+
+impl Prober for HTTPProber {
+    fn parse(url: &Url) -> Result<HTTPProber, ()> {
+        let (host, port) = match (url.host(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => (host_string(host), port),
+            _ => return Err(()),
+        };
+
+        let options = prober_options(url);
+        let addresses = AddressPolicy::from_options(&options)?;
+        let family = AddressFamily::from_options(&options)?;
+        let bind = Bind::from_options(&options)?;
+
+        // Strip replica options from the URL to be requested
+        let mut url = url.to_owned();
+
+        url.set_fragment(None);
+
+        Ok(HTTPProber {
+            url: url.to_string(),
+            host: host,
+            port: port,
+            addresses: addresses,
+            family: family,
+            bind: bind,
+        })
+    }
+
+    fn probe(
+        &self,
+        engine: &Engine,
+        node: &MapServiceNode,
+        metrics: &Option<MapMetrics>,
+    ) -> ProbeOutcome {
+        let bind = engine.bind_for(&self.bind);
+
+        // Notice: requests are pinned to resolved addresses, so that the engine resolver is \
+        //   used (and as each address gets requested over its own connection).
+        engine.probe_addresses(
+            &self.host,
+            self.port,
+            self.family,
+            self.addresses,
+            bind,
+            |address| {
+                let route = DirectRoute {
+                    ip: Some(address.ip()),
+                    bind: bind,
+                };
+
+                request(engine, &self.url, &self.host, &node.http, metrics, route)
+            },
+        )
+    }
+}
+
+fn request(
+    engine: &Engine,
+    url: &str,
+    host: &str,
+    http: &Option<MapServiceNodeHTTP>,
+    metrics: &Option<MapMetrics>,
+    route: DirectRoute,
+) -> ProbeOutcome {
+    debug!("prober poll will fire for http target: {}", &url);
+
+    // Unpack dead timeout
+    let dead_timeout = acquire_dead_timeout(metrics);
+
+    // Unpack HTTP request method, headers and body (if configured)
+    let default_method = if has_http_body_checks(http) == true {
+        Method::GET
+    } else {
+        Method::HEAD
+    };
+
+    let mut http_request = match acquire_http_request(engine, host, http, default_method) {
+        Ok(http_request) => http_request,
+        Err(reason) => {
+            warn!(
+                "could not prepare prober poll request for url: {} ({})",
+                &url, reason
+            );
+
+            // Consider as DOWN (the request could not be sent as configured)
+            return ProbeOutcome::dead(format!("request could not be prepared: {}", reason));
+        }
+    };
+
+    // Unpack HTTP redirect policy
+    let (redirects_max_hops, redirects_same_host, redirects_allow_downgrade) =
+        acquire_http_redirects(http);
+
+    // Acquire replica response (following redirects, if allowed)
+    // Notice: redirects are followed manually, as the HTTP library would otherwise follow them \
+    //   with its default timeouts, and without our custom request headers.
+    let mut response_body = Vec::new();
+    let mut final_url = url.to_owned();
+    let mut hops: u8 = 0;
+
+    let response = loop {
+        response_body.clear();
+
+        // Notice: redirects to another host are not pinned to the address being probed
+        let hop_route = DirectRoute {
+            ip: route.ip.filter(|_| is_same_http_host(url, &final_url)),
+            bind: route.bind,
+        };
+
+        let response = send_http_request(
+            engine,
+            &final_url,
+            &http_request,
+            dead_timeout,
+            hop_route,
+            &mut response_body,
+        );
+
+        // Notice: a redirect that cannot be followed (eg. a downgrade, or once the maximum \
+        //   number of hops is reached) is refused, and the replica is considered as DOWN, as the \
+        //   final response could not be checked. Redirects are not followed at all if disabled.
+        let redirect_url = match response {
+            Ok(ref response_inner)
+                if response_inner.status_code().is_redirect() == true && redirects_max_hops > 0 =>
+            {
+                match response_inner.headers().get("Location") {
+                    Some(_) if hops >= redirects_max_hops => Err("hop limit reached"),
+                    Some(location) => resolve_http_redirect(
+                        &final_url,
+                        location,
+                        redirects_same_host,
+                        redirects_allow_downgrade,
+                    )
+                    .map(|redirect_url| {
+                        Some((redirect_url, u16::from(response_inner.status_code())))
+                    }),
+                    None => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        };
+
+        match redirect_url {
+            Ok(Some((redirect_url, redirect_status))) => {
+                debug!(
+                    "prober poll following redirect for url: {} to: {} with status: {}",
+                    &final_url, &redirect_url, redirect_status
+                );
+
+                // Notice: as per browser behavior, 'POST' requests (and alike) become 'GET' \
+                //   requests when redirected with 301, 302 or 303 status codes.
+                if redirect_status <= 303
+                    && http_request.method != Method::GET
+                    && http_request.method != Method::HEAD
+                {
+                    http_request.method = Method::GET;
+                    http_request.body = None;
+                }
+
+                // Notice: custom headers may hold secrets, or a 'Host' header override, which \
+                //   must never be sent to another host.
+                if is_same_http_host(&final_url, &redirect_url) == false {
+                    http_request.headers.clear();
+
+                    // Notice: the same goes for a request body that holds secrets, as secrets \
+                    //   are only allowed for the original host.
+                    http_request.drop_secrets();
+                }
+
+                // Notice: secrets must never be sent in cleartext, even if downgrades are \
+                //   allowed by the map (as the map is pulled from a remote server).
+                if is_http_downgrade(&final_url, &redirect_url) == true {
+                    http_request.drop_secrets();
+                }
+
+                final_url = redirect_url;
+                hops += 1;
+            }
+            Ok(None) => break response,
+            Err(reason) => {
+                debug!(
+                    "prober poll refused redirect for url: {} ({})",
+                    &final_url, reason
+                );
+
+                return ProbeOutcome::dead(format!("redirect refused: {}", reason));
+            }
+        }
+    };
+
+    let mut outcome = handle_http_response(&final_url, response, &response_body, http);
+
+    // Notice: the final URL and hop count are reported along with the outcome reason, so that \
+    //   a failure on the final page can be told apart from a failure on the original URL.
+    if hops > 0 {
+        debug!(
+            "prober poll followed {} redirect(s) for url: {} to final url: {}",
+            hops, &url, &final_url
+        );
+
+        if let Some(ref mut reason) = outcome.reason {
+            reason.push_str(&format!(" (after {} redirect(s) to: {})", hops, &final_url));
+        }
+    }
+
+    outcome
+}
+
+fn handle_http_response(
+    url: &str,
+    response: Result<Response, ()>,
+    response_body: &[u8],
+    http: &Option<MapServiceNodeHTTP>,
+) -> ProbeOutcome {
+    if let Ok(response) = response {
+        let status_code = u16::from(response.status_code());
+
+        debug!(
+            "prober poll result received for url: {} with status: {}",
+            &url, status_code
+        );
+
+        // Unpack HTTP status codes
+        let mut http_healthy_above = HTTP_HEALTHY_ABOVE;
+        let mut http_healthy_below = HTTP_HEALTHY_BELOW;
+
+        if let &Some(ref http_inner) = http {
+            if let Some(ref http_inner_status_inner) = http_inner.status {
+                if let Some(healthy_above_inner) = http_inner_status_inner.healthy_above {
+                    http_healthy_above = healthy_above_inner;
+                }
+
+                if let Some(healthy_below_inner) = http_inner_status_inner.healthy_below {
+                    http_healthy_below = healthy_below_inner;
+                }
+            }
+        }
+
+        // Consider as SICK? (this takes precedence over the healthy status window)
+        let is_sick_code = http
+            .as_ref()
+            .and_then(|http_inner| http_inner.status.as_ref())
+            .and_then(|status_inner| status_inner.sick_codes.as_ref())
+            .map(|sick_codes| {
+                sick_codes.iter().any(|sick_code| match sick_code {
+                    &MapServiceNodeHTTPStatusCodes::Code(code) => status_code == code,
+                    &MapServiceNodeHTTPStatusCodes::Range { above, below } => {
+                        status_code >= above && status_code < below
+                    }
+                })
+            })
+            .unwrap_or(false);
+
+        if is_sick_code == true {
+            debug!(
+                "prober poll result for url: {} has sick status: {}",
+                &url, status_code
+            );
+
+            return ProbeOutcome::sick(format!("sick status code: {}", status_code));
+        }
+
+        // Consider as UP?
+        if status_code >= http_healthy_above && status_code < http_healthy_below {
+            // Check response body? (if configured)
+            return check_http_body(url, response_body, http, "response body");
+        }
+
+        // Consider as DOWN.
+        ProbeOutcome::dead(format!("unhealthy status code: {}", status_code))
+    } else {
+        debug!("prober poll result was not received for url: {}", &url);
+
+        // Consider as DOWN.
+        ProbeOutcome::dead("no response received")
+    }
+}
+
+fn send_http_request(
+    engine: &Engine,
+    url: &str,
+    http_request: &HTTPRequest,
+    timeout: Duration,
+    route: DirectRoute,
+    response_body: &mut Vec<u8>,
+) -> Result<Response, ()> {
+    // Generate request URI
+    let request_uri = match Uri::try_from(url) {
+        Ok(request_uri) => request_uri,
+        Err(_) => {
+            warn!("invalid prober poll request uri: {}", url);
+
+            return Err(());
+        }
+    };
+
+    // Send over our own connection? (if pinned to an address, bound to a local address or \
+    //   interface, or if a local TLS configuration is set)
+    if route.ip.is_some() == true
+        || route.bind.is_some() == true
+        || (request_uri.scheme() == "https" && engine.tls.is_some() == true)
+    {
+        let mut message = RequestMessage::new(&request_uri);
+
+        message
+            .method(http_request.method)
+            .header("Connection", "Close")
+            .header("User-Agent", &*HTTP_HEADER_USERAGENT);
+
+        for (header_name, header_value) in &http_request.headers {
+            message.header(header_name, header_value);
+        }
+
+        if let Some(ref request_body_inner) = http_request.body {
+            message.body(request_body_inner.as_bytes());
+        }
+
+        return direct_send(
+            engine,
+            &request_uri,
+            &message,
+            http_request.method,
+            timeout,
+            route,
+            response_body,
+        );
+    }
+
+    let mut request = Request::new(&request_uri);
+
+    request
+        .connect_timeout(Some(timeout))
+        .read_timeout(Some(timeout))
+        .write_timeout(Some(timeout))
+        .redirect_policy(RedirectPolicy::Limit(0))
+        .method(http_request.method)
+        .header("User-Agent", &*HTTP_HEADER_USERAGENT);
+
+    // Notice: custom headers are applied last, so that they can override default headers (eg. \
+    //   the 'Host' header, for virtual host routing).
+    for (header_name, header_value) in &http_request.headers {
+        request.header(header_name, header_value);
+    }
+
+    if let Some(ref request_body_inner) = http_request.body {
+        request.body(request_body_inner.as_bytes());
+    }
+
+    match request.send(response_body) {
+        Ok(response) => Ok(response),
+        Err(err) => {
+            debug!(
+                "prober poll request failed for url: {} (error: {})",
+                url, err
+            );
+
+            Err(())
+        }
+    }
+}
+
+fn acquire_http_redirects(http: &Option<MapServiceNodeHTTP>) -> (u8, bool, bool) {
+    let redirects = http
+        .as_ref()
+        .and_then(|http_inner| http_inner.redirects.as_ref());
+
+    (
+        redirects
+            .and_then(|redirects_inner| redirects_inner.max_hops)
+            .unwrap_or(HTTP_REDIRECTS_MAX_HOPS),
+        redirects
+            .and_then(|redirects_inner| redirects_inner.same_host)
+            .unwrap_or(false),
+        redirects
+            .and_then(|redirects_inner| redirects_inner.allow_downgrade)
+            .unwrap_or(false),
+    )
+}
+
+fn resolve_http_redirect(
+    url: &str,
+    location: &str,
+    same_host: bool,
+    allow_downgrade: bool,
+) -> Result<String, &'static str> {
+    // Resolve redirect location (which may be relative to the current URL)
+    let current_url = Url::parse(url).or(Err("invalid url"))?;
+
+    let redirect_url = match current_url.join(location) {
+        Ok(redirect_url) => redirect_url,
+        Err(err) => {
+            debug!(
+                "could not resolve redirect location for url: {}: {} (error: {})",
+                url, location, err
+            );
+
+            return Err("invalid location");
+        }
+    };
+
+    // Refuse to leave the HTTP protocol, to downgrade from HTTPS to HTTP, or to change host \
+    //   (if configured)
+    let refusal = match (current_url.scheme(), redirect_url.scheme()) {
+        ("http", "http") | ("http", "https") | ("https", "https") => None,
+        ("https", "http") if allow_downgrade == true => None,
+        ("https", "http") => Some("downgrade"),
+        _ => Some("scheme change"),
+    }
+    .or_else(|| {
+        if same_host == true && current_url.host_str() != redirect_url.host_str() {
+            Some("host change")
+        } else {
+            None
+        }
+    });
+
+    if let Some(refusal) = refusal {
+        debug!(
+            "refused to follow redirect for url: {} to: {} ({})",
+            url, redirect_url, refusal
+        );
+
+        return Err(refusal);
+    }
+
+    Ok(redirect_url.into())
+}
+
+fn is_http_downgrade(url: &str, other_url: &str) -> bool {
+    match (Url::parse(url), Url::parse(other_url)) {
+        (Ok(url_inner), Ok(other_url_inner)) => {
+            url_inner.scheme() == "https" && other_url_inner.scheme() == "http"
+        }
+        _ => false,
+    }
+}
+
+fn is_same_http_host(url: &str, other_url: &str) -> bool {
+    match (Url::parse(url), Url::parse(other_url)) {
+        (Ok(url_inner), Ok(other_url_inner)) => {
+            url_inner.host_str() == other_url_inner.host_str()
+                && url_inner.port_or_known_default() == other_url_inner.port_or_known_default()
+        }
+        _ => false,
+    }
+}
+
+fn acquire_http_request(
+    engine: &Engine,
+    host: &str,
+    http: &Option<MapServiceNodeHTTP>,
+    default_method: Method,
+) -> Result<HTTPRequest, &'static str> {
+    let (mut method, mut headers, mut headers_with_secrets, mut body, mut body_has_secrets) =
+        (default_method, Vec::new(), Vec::new(), None, false);
+
+    if let Some(http_request) = http
+        .as_ref()
+        .and_then(|http_inner| http_inner.request.as_ref())
+    {
+        if let Some(ref method_inner) = http_request.method {
+            method = match method_inner.to_uppercase().as_str() {
+                "GET" => Method::GET,
+                "HEAD" => Method::HEAD,
+                "POST" => Method::POST,
+                "PUT" => Method::PUT,
+                "DELETE" => Method::DELETE,
+                "OPTIONS" => Method::OPTIONS,
+                "PATCH" => Method::PATCH,
+                _ => {
+                    warn!("unsupported http request method: {}", method_inner);
+
+                    return Err("unsupported request method");
+                }
+            };
+        }
+
+        // Substitute secrets in header values and body (secrets are never stored in the map)
+        if let Some(ref headers_inner) = http_request.headers {
+            for (header_name, header_value) in headers_inner {
+                headers.push((
+                    header_name.to_owned(),
+                    secret_substitute(engine, header_value, host)?,
+                ));
+
+                if secret_contains(header_value) == true {
+                    headers_with_secrets.push(header_name.to_owned());
+                }
+            }
+        }
+
+        if let Some(ref body_inner) = http_request.body {
+            body = Some(secret_substitute(engine, body_inner, host)?);
+            body_has_secrets = secret_contains(body_inner);
+        }
+    }
+
+    Ok(HTTPRequest {
+        method: method,
+        headers: headers,
+        headers_with_secrets: headers_with_secrets,
+        body: body,
+        body_has_secrets: body_has_secrets,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::super::status::Status;
+
+    fn serve(responses: Vec<&'static str>) -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+
+            // Serve each response over its own connection (as requests are sent with a \
+            //   'Connection: Close' header)
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut byte = [0; 1];
+
+                while request.ends_with(b"\r\n\r\n") == false {
+                    stream.read_exact(&mut byte).unwrap();
+
+                    request.push(byte[0]);
+                }
+
+                stream.write_all(response.as_bytes()).unwrap();
+
+                requests.push(String::from_utf8_lossy(&request).to_string());
+            }
+
+            requests
+        });
+
+        (port, server)
+    }
+
+    fn probe(port: u16, http: &str) -> ProbeOutcome {
+        let node: MapServiceNode =
+            serde_json::from_str(&format!(r#"{{"id": "node", "http": {}}}"#, http)).unwrap();
+
+        HTTPProber::parse(&Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap())
+            .unwrap()
+            .probe(&Engine::builder().build(), &node, &None)
+    }
+
+    #[test]
+    fn reports_sick_status_codes() {
+        let (port, server) = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 525 SSL Handshake Failed\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+        ]);
+
+        let http = r#"{"status": {"sick_codes": [503, {"above": 520, "below": 530}]}}"#;
+
+        let outcome = probe(port, http);
+
+        assert_eq!(outcome.status, Status::Sick);
+        assert_eq!(outcome.reason, Some("sick status code: 503".to_string()));
+
+        assert_eq!(probe(port, http).status, Status::Sick);
+
+        let outcome = probe(port, http);
+
+        assert_eq!(outcome.status, Status::Dead);
+        assert_eq!(
+            outcome.reason,
+            Some("unhealthy status code: 500".to_string())
+        );
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reports_sick_body_match() {
+        let (port, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 14\r\n\r\nstatus: degrad",
+            "HTTP/1.1 200 OK\r\nContent-Length: 16\r\n\r\nstatus: degraded",
+        ]);
+
+        let http = r#"{"body": {"sick_match": "degraded"}}"#;
+
+        assert_eq!(probe(port, http).status, Status::Healthy);
+
+        let outcome = probe(port, http);
+
+        assert_eq!(outcome.status, Status::Sick);
+        assert_eq!(
+            outcome.reason,
+            Some("response body has sick match".to_string())
+        );
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reports_final_url_of_redirects() {
+        let (port, server) = serve(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /next\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+        ]);
+
+        let outcome = probe(port, "{}");
+
+        assert_eq!(outcome.status, Status::Dead);
+        assert_eq!(
+            outcome.reason,
+            Some(format!(
+                "unhealthy status code: 500 (after 1 redirect(s) to: http://127.0.0.1:{}/next)",
+                port
+            ))
+        );
+
+        let requests = server.join().unwrap();
+
+        assert!(requests[1].starts_with("HEAD /next HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn drops_secrets_on_downgrade() {
+        let mut http_request = HTTPRequest {
+            method: Method::POST,
+            headers: vec![
+                ("Authorization".to_string(), "Bearer token".to_string()),
+                ("Accept".to_string(), "text/plain".to_string()),
+            ],
+            headers_with_secrets: vec!["Authorization".to_string()],
+            body: Some("token".to_string()),
+            body_has_secrets: true,
+        };
+
+        assert!(is_http_downgrade("https://a.local/", "http://a.local/"));
+        assert!(!is_http_downgrade("http://a.local/", "https://a.local/"));
+
+        http_request.drop_secrets();
+
+        assert_eq!(
+            http_request.headers,
+            vec![("Accept".to_string(), "text/plain".to_string())]
+        );
+        assert_eq!(http_request.body, None);
+    }
+
+    #[test]
+    fn follows_allowed_redirects() {
+        assert_eq!(
+            resolve_http_redirect("http://a.local/x", "/y", true, false),
+            Ok("http://a.local/y".to_string())
+        );
+        assert_eq!(
+            resolve_http_redirect("http://a.local/", "https://a.local/", true, false),
+            Ok("https://a.local/".to_string())
+        );
+    }
+
+    #[test]
+    fn refuses_downgrade_and_host_change() {
+        assert_eq!(
+            resolve_http_redirect("https://a.local/", "http://a.local/", false, false),
+            Err("downgrade")
+        );
+        assert_eq!(
+            resolve_http_redirect("http://a.local/", "http://b.local/", true, false),
+            Err("host change")
+        );
+        assert_eq!(
+            resolve_http_redirect("http://a.local/", "ftp://a.local/", false, false),
+            Err("scheme change")
+        );
+    }
+}
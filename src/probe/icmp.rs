@@ -0,0 +1,459 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use fastping_rs::{PingResult, Pinger};
+use url::Url;
+
+use std::cmp::min;
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+
+use super::address::AddressFamily;
+use super::bind::Bind;
+use super::echo::{EchoKind, EchoSocket};
+use super::engine::Engine;
+use super::map::{MapMetrics, MapServiceNode};
+use super::prober::{
+    acquire_dead_timeout, host_string, option as prober_option, options as prober_options,
+    ProbeOutcome, Prober,
+};
+use super::status::Status;
+
+const ICMP_TIMEOUT_MILLISECONDS: u64 = 1000;
+const ICMP_PACKETS_DEFAULT: u16 = 1;
+const ICMP_PACKETS_MAXIMUM: u16 = 100;
+const ICMP_INTERVAL_MILLISECONDS_DEFAULT: u64 = 200;
+const ICMP_LOSS_SICK_DEFAULT: u8 = 20;
+const ICMP_LOSS_DEAD_DEFAULT: u8 = 100;
+
+#[derive(Debug)]
+pub struct ICMPProber {
+    host: String,
+    family: Option<AddressFamily>,
+    bind: Option<Bind>,
+    packets: u16,
+    interval: Duration,
+    loss_sick: u8,
+    loss_dead: u8,
+    jitter_sick: Option<Duration>,
+}
+
+struct ICMPSamples {
+    address: IpAddr,
+    rtts: Vec<Option<Duration>>,
+}
+
+impl Prober for ICMPProber {
+    fn parse(url: &Url) -> Result<ICMPProber, ()> {
+        let options = prober_options(url);
+
+        // Notice: multiple packets can be sent to each address (eg. \
+        //   'icmp://gateway.local#packets=10&loss_sick=10'), in which case the replica gets \
+        //   reported as sick or dead depending on the percentage of lost packets.
+        let packets = prober_option(&options, "packets")?.unwrap_or(ICMP_PACKETS_DEFAULT);
+        let interval =
+            prober_option(&options, "interval")?.unwrap_or(ICMP_INTERVAL_MILLISECONDS_DEFAULT);
+        let loss_sick = prober_option(&options, "loss_sick")?.unwrap_or(ICMP_LOSS_SICK_DEFAULT);
+        let loss_dead = prober_option(&options, "loss_dead")?.unwrap_or(ICMP_LOSS_DEAD_DEFAULT);
+        let jitter_sick: Option<u64> = prober_option(&options, "jitter_sick")?;
+
+        if packets == 0 || packets > ICMP_PACKETS_MAXIMUM || loss_sick > 100 || loss_dead > 100 {
+            return Err(());
+        }
+
+        match url.host() {
+            Some(host) => Ok(ICMPProber {
+                host: host_string(host),
+                family: AddressFamily::from_options(&options)?,
+                bind: Bind::from_options(&options)?,
+                packets: packets,
+                interval: Duration::from_millis(interval),
+                loss_sick: loss_sick,
+                loss_dead: loss_dead,
+                jitter_sick: jitter_sick.map(Duration::from_millis),
+            }),
+            _ => Err(()),
+        }
+    }
+
+    fn probe(
+        &self,
+        engine: &Engine,
+        _node: &MapServiceNode,
+        metrics: &Option<MapMetrics>,
+    ) -> ProbeOutcome {
+        let host = &self.host;
+
+        // Notice: ICMP support is detected once, when the engine gets built
+        let echo_kind = match engine.icmp {
+            Some(echo_kind) => echo_kind,
+            None => {
+                return ProbeOutcome::dead(
+                    "icmp is unsupported (requires CAP_NET_RAW or net.ipv4.ping_group_range)",
+                )
+            }
+        };
+
+        // Notice: a dummy port of value '0' is set here, as ICMP does not use ports.
+        let address_values = match engine.resolve(host, 0) {
+            Ok(address_values) => address_values,
+            Err(err) => {
+                debug!(
+                    "prober poll address for icmp replica is unresolved: {} (error: {})",
+                    host, err
+                );
+
+                // Consider unresolved host as a failure
+                return ProbeOutcome::unresolved(err);
+            }
+        };
+
+        // As ICMP pings require a lower-than-usual timeout, an hard-coded ICMP timeout value is \
+        //   used by default, though the configured dead delay value is preferred in the event \
+        //   it is lower than the hard-coded value (unlikely though possible in some setups).
+        let pinger_timeout = min(
+            ICMP_TIMEOUT_MILLISECONDS,
+            acquire_dead_timeout(metrics).as_secs() * 1000,
+        );
+
+        let bind = engine.bind_for(&self.bind);
+
+        let address_values = match engine.filter_for_bind(address_values, bind) {
+            Ok(address_values) => address_values,
+            Err(outcome) => return outcome,
+        };
+
+        let family = self.family.unwrap_or(engine.family);
+
+        family.probe(address_values, |address_values| {
+            // Notice: the ICMP probe checker is a bit special, in the sense that it checks all \
+            //   resolved addresses. As we check for an host health at the IP level (ie. not at \
+            //   the application layer level), checking only the first host in the list is not \
+            //   sufficient for the whole replica group to be up. This can be used as an handy \
+            //   way to check for the health of a group of IP hosts, configured in a single DNS \
+            //   record.
+            let mut address_ips: Vec<IpAddr> = Vec::new();
+
+            for address_value in &address_values {
+                if address_ips.contains(&address_value.ip()) == false {
+                    address_ips.push(address_value.ip());
+                }
+            }
+
+            if address_ips.is_empty() == true {
+                debug!(
+                    "prober poll did not resolve any address for icmp replica: {}",
+                    host
+                );
+
+                // Consider empty as a failure
+                return ProbeOutcome::dead("no address resolved");
+            }
+
+            // Notice: the pinger library is only used for single-packet probes, as it times \
+            //   replies from a timer that gets reset after its requests are sent, which would \
+            //   inflate round-trip-times over multiple rounds.
+            let samples = match (echo_kind, bind) {
+                (EchoKind::Raw, None) if self.packets == 1 => {
+                    self.ping(&address_ips, pinger_timeout)
+                }
+                (echo_kind, bind) => self.ping_echo(&address_ips, echo_kind, bind, pinger_timeout),
+            };
+
+            match samples {
+                Ok(samples) => self.evaluate(samples),
+                Err(outcome) => outcome,
+            }
+        })
+    }
+}
+
+impl ICMPProber {
+    fn ping(
+        &self,
+        address_ips: &[IpAddr],
+        pinger_timeout: u64,
+    ) -> Result<Vec<ICMPSamples>, ProbeOutcome> {
+        let host = &self.host;
+
+        debug!(
+            "prober poll will fire for icmp host: {} ({} targets)",
+            host,
+            address_ips.len()
+        );
+
+        let (pinger, results) = match Pinger::new(Some(pinger_timeout), None) {
+            Ok(pinger) => pinger,
+            Err(err) => {
+                warn!("failed to create icmp pinger (error: {})", err);
+
+                return Err(ProbeOutcome::dead(format!("icmp pinger error: {}", err)));
+            }
+        };
+
+        let mut samples = new_samples(address_ips);
+
+        // Probe all returned addresses (sequentially)
+        for address_ip in address_ips {
+            debug!(
+                "prober poll will send icmp ping to target: {} from host: {}",
+                address_ip, host
+            );
+
+            pinger.add_ipaddr(&address_ip.to_string());
+        }
+
+        pinger.ping_once();
+
+        for _ in address_ips {
+            let (addr, rtt) = match results.recv() {
+                Ok(PingResult::Receive { addr, rtt }) => {
+                    debug!(
+                        "got prober poll result for icmp target: {} from host: {}",
+                        addr, host
+                    );
+
+                    (addr, Some(rtt))
+                }
+                Ok(PingResult::Idle { addr }) => {
+                    debug!(
+                        "prober poll host idle for icmp target: {} from host: {}",
+                        addr, host
+                    );
+
+                    // Consider ICMP idle hosts as a lost packet (ie. routable, but unreachable)
+                    (addr, None)
+                }
+                Err(err) => {
+                    debug!("prober poll error for icmp host: {} (error: {})", host, err);
+
+                    // Consider ICMP errors as a failure
+                    return Err(ProbeOutcome::dead(format!("icmp error: {}", err)));
+                }
+            };
+
+            if let Some(sample) = samples.iter_mut().find(|sample| sample.address == addr) {
+                sample.rtts.push(rtt);
+            }
+        }
+
+        Ok(samples)
+    }
+
+    fn ping_echo(
+        &self,
+        address_ips: &[IpAddr],
+        echo_kind: EchoKind,
+        bind: Option<&Bind>,
+        pinger_timeout: u64,
+    ) -> Result<Vec<ICMPSamples>, ProbeOutcome> {
+        let host = &self.host;
+        let mut samples = new_samples(address_ips);
+
+        // Notice: as the pinger library cannot bind its sockets to a source address or an \
+        //   interface, nor use unprivileged sockets, nor send multiple packets, each address \
+        //   gets pinged over its own echo socket (sequentially).
+        for sample in samples.iter_mut() {
+            debug!(
+                "prober poll will send {:?} icmp pings to target: {} from host: {} (bind: {:?})",
+                echo_kind, sample.address, host, bind
+            );
+
+            let mut socket = EchoSocket::open(sample.address, echo_kind, bind).map_err(|err| {
+                debug!("prober poll error for icmp host: {} (error: {})", host, err);
+
+                ProbeOutcome::dead(format!("icmp error: {}", err))
+            })?;
+
+            for sequence in 0..self.packets {
+                if sequence > 0 {
+                    thread::sleep(self.interval);
+                }
+
+                let rtt = socket
+                    .ping(sequence, Duration::from_millis(pinger_timeout))
+                    .map_err(|err| {
+                        debug!("prober poll error for icmp host: {} (error: {})", host, err);
+
+                        ProbeOutcome::dead(format!("icmp error: {}", err))
+                    })?;
+
+                sample.rtts.push(rtt);
+            }
+        }
+
+        Ok(samples)
+    }
+
+    fn evaluate(&self, samples: Vec<ICMPSamples>) -> ProbeOutcome {
+        let (mut status, mut latency, mut reasons) = (Status::Healthy, None, Vec::new());
+
+        for sample in samples {
+            let rtts: Vec<Duration> = sample.rtts.iter().flatten().copied().collect();
+
+            // Notice: missing samples are accounted as lost packets
+            let loss = 100 - rtts.len() * 100 / self.packets as usize;
+            let loss = loss as u8;
+
+            let (average_rtt, maximum_rtt) = match rtts.iter().max() {
+                Some(maximum_rtt) => (
+                    Some(rtts.iter().sum::<Duration>() / rtts.len() as u32),
+                    Some(*maximum_rtt),
+                ),
+                None => (None, None),
+            };
+
+            // Compute jitter (ie. mean difference between consecutive round-trip-times)
+            let jitter = if rtts.len() > 1 {
+                let differences: Duration = rtts
+                    .iter()
+                    .zip(rtts.iter().skip(1))
+                    .map(|(previous, next)| {
+                        if next > previous {
+                            *next - *previous
+                        } else {
+                            *previous - *next
+                        }
+                    })
+                    .sum();
+
+                Some(differences / (rtts.len() - 1) as u32)
+            } else {
+                None
+            };
+
+            debug!(
+                "got icmp statistics for target: {} from host: {} (loss: {}%, rtt avg: {:?}, \
+                    rtt max: {:?}, jitter: {:?})",
+                sample.address, self.host, loss, average_rtt, maximum_rtt, jitter
+            );
+
+            // Report the maximum round-trip-time over all addresses as latency (so that a \
+            //   single slow reply gets compared to the sick delay, as with single-packet probes)
+            latency = match (latency, maximum_rtt) {
+                (Some(latency), Some(maximum_rtt)) => Some(maximum_rtt.max(latency)),
+                (latency, maximum_rtt) => latency.or(maximum_rtt),
+            };
+
+            if loss > 0 && loss >= self.loss_dead {
+                status = Status::Dead;
+            } else if loss > 0 && loss >= self.loss_sick {
+                if let Status::Healthy = status {
+                    status = Status::Sick;
+                }
+            } else {
+                // Report as sick on high jitter? (only if the jitter threshold is set)
+                match (jitter, self.jitter_sick) {
+                    (Some(jitter), Some(jitter_sick)) if jitter >= jitter_sick => {
+                        if let Status::Healthy = status {
+                            status = Status::Sick;
+                        }
+
+                        reasons.push(format!(
+                            "{}ms jitter from target: {}",
+                            jitter.as_millis(),
+                            sample.address
+                        ));
+                    }
+                    _ => {}
+                }
+
+                continue;
+            }
+
+            reasons.push(format!(
+                "{}% packet loss from target: {}",
+                loss, sample.address
+            ));
+        }
+
+        let mut outcome = ProbeOutcome::from_status(status, reasons.join(", "));
+
+        outcome.latency = latency;
+
+        outcome
+    }
+}
+
+fn new_samples(address_ips: &[IpAddr]) -> Vec<ICMPSamples> {
+    address_ips
+        .iter()
+        .map(|address_ip| ICMPSamples {
+            address: *address_ip,
+            rtts: Vec::new(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prober(packets: u16, jitter_sick: Option<u64>) -> ICMPProber {
+        ICMPProber {
+            host: "gateway.local".to_string(),
+            family: None,
+            bind: None,
+            packets: packets,
+            interval: Duration::from_millis(ICMP_INTERVAL_MILLISECONDS_DEFAULT),
+            loss_sick: ICMP_LOSS_SICK_DEFAULT,
+            loss_dead: ICMP_LOSS_DEAD_DEFAULT,
+            jitter_sick: jitter_sick.map(Duration::from_millis),
+        }
+    }
+
+    fn samples(rtts: &[Option<u64>]) -> Vec<ICMPSamples> {
+        vec![ICMPSamples {
+            address: "192.0.2.1".parse().unwrap(),
+            rtts: rtts
+                .iter()
+                .map(|rtt| rtt.map(Duration::from_millis))
+                .collect(),
+        }]
+    }
+
+    #[test]
+    fn it_reports_maximum_rtt_as_latency() {
+        let outcome = prober(3, None).evaluate(samples(&[Some(10), Some(40), Some(10)]));
+
+        assert_eq!(outcome.status, Status::Healthy);
+        assert_eq!(outcome.latency, Some(Duration::from_millis(40)));
+    }
+
+    #[test]
+    fn it_reports_jitter_above_threshold_as_sick() {
+        let rtts = [Some(10), Some(40), Some(10)];
+
+        assert_eq!(
+            prober(3, None).evaluate(samples(&rtts)).status,
+            Status::Healthy
+        );
+        assert_eq!(
+            prober(3, Some(50)).evaluate(samples(&rtts)).status,
+            Status::Healthy
+        );
+
+        let outcome = prober(3, Some(20)).evaluate(samples(&rtts));
+
+        assert_eq!(outcome.status, Status::Sick);
+        assert_eq!(
+            outcome.reason,
+            Some("30ms jitter from target: 192.0.2.1".to_string())
+        );
+    }
+
+    #[test]
+    fn it_reports_packet_loss() {
+        let outcome = prober(4, Some(20)).evaluate(samples(&[Some(10), None, None, None]));
+
+        assert_eq!(outcome.status, Status::Sick);
+        assert_eq!(
+            outcome.reason,
+            Some("75% packet loss from target: 192.0.2.1".to_string())
+        );
+    }
+}
@@ -0,0 +1,283 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use serde_json::{self, Value};
+
+use std::cmp::Ordering;
+
+#[derive(Debug)]
+pub struct JSONRule {
+    path: Vec<JSONPathSegment>,
+    comparison: Option<(JSONOperator, Value)>,
+}
+
+#[derive(Debug)]
+enum JSONPathSegment {
+    Key(String),
+    Index(i64),
+    Wildcard,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum JSONOperator {
+    Equal,
+    NotEqual,
+    Lower,
+    LowerOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl JSONRule {
+    pub fn parse(rule: &str) -> Result<JSONRule, ()> {
+        // Rules are formatted as a JSONPath expression, optionally followed by a comparison \
+        //   operator and a JSON literal (eg. '$.status == "UP"', or '$.checks[*].state != \
+        //   "fail"'); rules without a comparison check that the path resolves to some value.
+        let rule = rule.trim();
+
+        if rule.starts_with('$') == false {
+            return Err(());
+        }
+
+        let (path, rest) = Self::parse_path(&rule[1..])?;
+        let rest = rest.trim_start();
+
+        let comparison = if rest.is_empty() == true {
+            None
+        } else {
+            let (operator, literal) = if let Some(literal) = rest.strip_prefix("==") {
+                (JSONOperator::Equal, literal)
+            } else if let Some(literal) = rest.strip_prefix("!=") {
+                (JSONOperator::NotEqual, literal)
+            } else if let Some(literal) = rest.strip_prefix("<=") {
+                (JSONOperator::LowerOrEqual, literal)
+            } else if let Some(literal) = rest.strip_prefix(">=") {
+                (JSONOperator::GreaterOrEqual, literal)
+            } else if let Some(literal) = rest.strip_prefix('<') {
+                (JSONOperator::Lower, literal)
+            } else if let Some(literal) = rest.strip_prefix('>') {
+                (JSONOperator::Greater, literal)
+            } else {
+                return Err(());
+            };
+
+            Some((operator, serde_json::from_str(literal.trim()).or(Err(()))?))
+        };
+
+        Ok(JSONRule {
+            path: path,
+            comparison: comparison,
+        })
+    }
+
+    pub fn evaluate(&self, document: &Value) -> bool {
+        let values = self.resolve(document);
+
+        // Notice: rules fail whenever their path resolves to nothing, whatever their operator, \
+        //   so that a typo in a path (eg. '$.chekcs[*].state != "fail"') never passes.
+        if values.is_empty() == true {
+            return false;
+        }
+
+        match self.comparison {
+            None => true,
+            Some((operator, ref literal)) => values.iter().all(|value| {
+                let ordering = Self::compare(value, literal);
+
+                match operator {
+                    JSONOperator::Equal => ordering == Some(Ordering::Equal),
+                    JSONOperator::NotEqual => ordering != Some(Ordering::Equal),
+                    JSONOperator::Lower => ordering == Some(Ordering::Less),
+                    JSONOperator::LowerOrEqual => {
+                        ordering == Some(Ordering::Less) || ordering == Some(Ordering::Equal)
+                    }
+                    JSONOperator::Greater => ordering == Some(Ordering::Greater),
+                    JSONOperator::GreaterOrEqual => {
+                        ordering == Some(Ordering::Greater) || ordering == Some(Ordering::Equal)
+                    }
+                }
+            }),
+        }
+    }
+
+    fn resolve<'a>(&self, document: &'a Value) -> Vec<&'a Value> {
+        let mut values = vec![document];
+
+        for segment in &self.path {
+            values = values
+                .into_iter()
+                .flat_map(|value| -> Vec<&'a Value> {
+                    match (segment, value) {
+                        (JSONPathSegment::Key(key), Value::Object(map)) => {
+                            map.get(key).into_iter().collect()
+                        }
+                        (JSONPathSegment::Index(index), Value::Array(array)) => {
+                            // Negative indexes start from the end of the array
+                            let index = if *index < 0 {
+                                array.len() as i64 + index
+                            } else {
+                                *index
+                            };
+
+                            if index >= 0 {
+                                array.get(index as usize).into_iter().collect()
+                            } else {
+                                Vec::new()
+                            }
+                        }
+                        (JSONPathSegment::Wildcard, Value::Array(array)) => array.iter().collect(),
+                        (JSONPathSegment::Wildcard, Value::Object(map)) => map.values().collect(),
+                        _ => Vec::new(),
+                    }
+                })
+                .collect();
+        }
+
+        values
+    }
+
+    fn compare(value: &Value, literal: &Value) -> Option<Ordering> {
+        // Notice: numbers are compared as floats, so that eg. '1' and '1.0' are equal, while \
+        //   other values can only be compared for equality.
+        match (value, literal) {
+            (Value::Number(value_number), Value::Number(literal_number)) => value_number
+                .as_f64()
+                .and_then(|value_float| value_float.partial_cmp(&literal_number.as_f64()?)),
+            _ => {
+                if value == literal {
+                    Some(Ordering::Equal)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn parse_path(mut path: &str) -> Result<(Vec<JSONPathSegment>, &str), ()> {
+        let mut segments = Vec::new();
+
+        loop {
+            if let Some(rest) = path.strip_prefix(".*") {
+                segments.push(JSONPathSegment::Wildcard);
+
+                path = rest;
+            } else if let Some(rest) = path.strip_prefix('.') {
+                let size = rest
+                    .find(|character: char| {
+                        character.is_alphanumeric() == false && character != '_' && character != '-'
+                    })
+                    .unwrap_or(rest.len());
+
+                if size == 0 {
+                    return Err(());
+                }
+
+                segments.push(JSONPathSegment::Key(rest[..size].to_owned()));
+
+                path = &rest[size..];
+            } else if let Some(rest) = path.strip_prefix('[') {
+                let end = rest.find(']').ok_or(())?;
+                let selector = rest[..end].trim();
+
+                segments.push(if selector == "*" {
+                    JSONPathSegment::Wildcard
+                } else if let Ok(index) = selector.parse::<i64>() {
+                    JSONPathSegment::Index(index)
+                } else if selector.len() >= 2
+                    && ((selector.starts_with('\'') && selector.ends_with('\''))
+                        || (selector.starts_with('"') && selector.ends_with('"')))
+                {
+                    JSONPathSegment::Key(selector[1..(selector.len() - 1)].to_owned())
+                } else {
+                    return Err(());
+                });
+
+                path = &rest[(end + 1)..];
+            } else {
+                return Ok((segments, path));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document() -> Value {
+        serde_json::from_str(
+            r#"{
+                "status": "UP",
+                "uptime": 42,
+                "load": 0.5,
+                "checks": [
+                    {"name": "db", "state": "pass"},
+                    {"name": "cache", "state": "pass"}
+                ],
+                "has space": true
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn evaluate(rule: &str) -> bool {
+        JSONRule::parse(rule).unwrap().evaluate(&document())
+    }
+
+    #[test]
+    fn it_parses_paths() {
+        assert!(evaluate("$.status"));
+        assert!(evaluate("$.checks[*].state"));
+        assert!(evaluate("$.checks.*.name"));
+        assert!(evaluate("$.checks[0].name == \"db\""));
+        assert!(evaluate("$.checks[-1].name == \"cache\""));
+        assert!(evaluate("$['has space'] == true"));
+        assert!(evaluate("$[\"checks\"][1][\"state\"] == \"pass\""));
+        assert!(evaluate("  $.status==\"UP\"  "));
+    }
+
+    #[test]
+    fn it_rejects_malformed_rules() {
+        assert!(JSONRule::parse("status == \"UP\"").is_err());
+        assert!(JSONRule::parse("$.").is_err());
+        assert!(JSONRule::parse("$.checks[0").is_err());
+        assert!(JSONRule::parse("$.checks[first]").is_err());
+        assert!(JSONRule::parse("$.status = \"UP\"").is_err());
+        assert!(JSONRule::parse("$.status == UP").is_err());
+        assert!(JSONRule::parse("$.status ==").is_err());
+    }
+
+    #[test]
+    fn it_evaluates_operators() {
+        assert!(evaluate("$.status == \"UP\""));
+        assert!(!evaluate("$.status == \"DOWN\""));
+        assert!(evaluate("$.checks[*].state != \"fail\""));
+        assert!(!evaluate("$.checks[*].state != \"pass\""));
+        assert!(evaluate("$.uptime > 10"));
+        assert!(!evaluate("$.uptime > 42"));
+        assert!(evaluate("$.uptime >= 42.0"));
+        assert!(evaluate("$.load < 1"));
+        assert!(!evaluate("$.load < 0.5"));
+        assert!(evaluate("$.load <= 0.5"));
+        assert!(!evaluate("$.status > 1"));
+    }
+
+    #[test]
+    fn it_fails_every_operator_on_empty_match() {
+        for rule in &[
+            "$.missing",
+            "$.missing == \"UP\"",
+            "$.chekcs[*].state != \"fail\"",
+            "$.missing < 1",
+            "$.missing <= 1",
+            "$.missing > 1",
+            "$.missing >= 1",
+            "$.checks[5].state != \"fail\"",
+        ] {
+            assert!(!evaluate(rule), "rule should fail: {}", rule);
+        }
+    }
+}
@@ -0,0 +1,610 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use url::Url;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use super::address::{AddressFamily, AddressPolicy};
+use super::bind::{connect as bind_connect, Bind};
+use super::engine::Engine;
+use super::map::{MapMetrics, MapServiceNode};
+use super::prober::{
+    acquire_dead_timeout, host_string, option as prober_option, options as prober_options,
+    ProbeOutcome, Prober,
+};
+use super::status::Status;
+use super::tls::connect as tls_connect;
+
+const MAIL_LINE_MAXIMUM_SIZE: usize = 4096;
+const MAIL_REPLY_MAXIMUM_LINES: usize = 512;
+const MAIL_SMTP_EHLO_DOMAIN: &'static str = "localhost";
+const MAIL_IMAP_TAG_CAPABILITY: &'static str = "a1";
+const MAIL_IMAP_TAG_STARTTLS: &'static str = "a2";
+const MAIL_IMAP_TAG_LOGOUT: &'static str = "a3";
+
+#[derive(Debug, Clone, Copy)]
+pub enum MailProtocol {
+    SMTP,
+    IMAP,
+    POP3,
+}
+
+#[derive(Debug)]
+pub struct MailProber {
+    protocol: MailProtocol,
+    host: String,
+    port: u16,
+    starttls: bool,
+    addresses: AddressPolicy,
+    family: Option<AddressFamily>,
+    bind: Option<Bind>,
+}
+
+#[derive(Clone, Copy)]
+enum MailStep {
+    Greeting,
+    Capability,
+    StartTLS,
+    Quit,
+}
+
+struct MailSession<S: Read + Write> {
+    stream: S,
+    buffer: Vec<u8>,
+}
+
+impl Prober for MailProber {
+    fn parse(url: &Url) -> Result<MailProber, ()> {
+        let protocol = match url.scheme() {
+            "smtp" => MailProtocol::SMTP,
+            "imap" => MailProtocol::IMAP,
+            "pop3" => MailProtocol::POP3,
+            _ => return Err(()),
+        };
+
+        let options = prober_options(url);
+
+        // Notice: STARTTLS is opt-in, using the 'starttls' replica option (eg. \
+        //   'smtp://mail.local:587#starttls=true'), as implicit TLS ports are not covered here.
+        let starttls = prober_option(&options, "starttls")?.unwrap_or(false);
+
+        match url.host() {
+            Some(host) => Ok(MailProber {
+                protocol: protocol,
+                host: host_string(host),
+                port: url.port().unwrap_or(protocol.default_port()),
+                starttls: starttls,
+                addresses: AddressPolicy::from_options(&options)?,
+                family: AddressFamily::from_options(&options)?,
+                bind: Bind::from_options(&options)?,
+            }),
+            _ => Err(()),
+        }
+    }
+
+    fn probe(
+        &self,
+        engine: &Engine,
+        _node: &MapServiceNode,
+        metrics: &Option<MapMetrics>,
+    ) -> ProbeOutcome {
+        let dead_timeout = acquire_dead_timeout(metrics);
+        let bind = engine.bind_for(&self.bind);
+
+        engine.probe_addresses(
+            &self.host,
+            self.port,
+            self.family,
+            self.addresses,
+            bind,
+            |address| {
+                debug!(
+                    "prober poll will fire for {} target: {}",
+                    self.protocol.as_str(),
+                    address
+                );
+
+                match bind_connect(bind, &address, dead_timeout) {
+                    Ok(stream) => {
+                        // Notice: as mail servers are expected to greet right after the \
+                        //   connection is opened, a server that hangs before sending its \
+                        //   greeting is considered dead.
+                        if stream.set_read_timeout(Some(dead_timeout)).is_ok()
+                            && stream.set_write_timeout(Some(dead_timeout)).is_ok()
+                        {
+                            ProbeOutcome::from_status(
+                                converse(self.protocol, stream, &self.host, self.starttls),
+                                format!("unexpected {} reply", self.protocol.as_str()),
+                            )
+                        } else {
+                            ProbeOutcome::dead("could not set socket timeouts")
+                        }
+                    }
+                    Err(err) => ProbeOutcome::dead(format!("connection failed: {}", err)),
+                }
+            },
+        )
+    }
+}
+
+impl MailProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &MailProtocol::SMTP => "smtp",
+            &MailProtocol::IMAP => "imap",
+            &MailProtocol::POP3 => "pop3",
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            &MailProtocol::SMTP => 25,
+            &MailProtocol::IMAP => 143,
+            &MailProtocol::POP3 => 110,
+        }
+    }
+
+    fn command(&self, step: MailStep) -> Option<String> {
+        match (self, step) {
+            (_, MailStep::Greeting) => None,
+            (&MailProtocol::SMTP, MailStep::Capability) => {
+                Some(format!("EHLO {}", MAIL_SMTP_EHLO_DOMAIN))
+            }
+            (&MailProtocol::SMTP, MailStep::StartTLS) => Some("STARTTLS".to_string()),
+            (&MailProtocol::SMTP, MailStep::Quit) => Some("QUIT".to_string()),
+            (&MailProtocol::IMAP, MailStep::Capability) => {
+                Some(format!("{} CAPABILITY", MAIL_IMAP_TAG_CAPABILITY))
+            }
+            (&MailProtocol::IMAP, MailStep::StartTLS) => {
+                Some(format!("{} STARTTLS", MAIL_IMAP_TAG_STARTTLS))
+            }
+            (&MailProtocol::IMAP, MailStep::Quit) => {
+                Some(format!("{} LOGOUT", MAIL_IMAP_TAG_LOGOUT))
+            }
+            (&MailProtocol::POP3, MailStep::Capability) => Some("CAPA".to_string()),
+            (&MailProtocol::POP3, MailStep::StartTLS) => Some("STLS".to_string()),
+            (&MailProtocol::POP3, MailStep::Quit) => Some("QUIT".to_string()),
+        }
+    }
+}
+
+impl<S: Read + Write> MailSession<S> {
+    fn new(stream: S) -> MailSession<S> {
+        MailSession {
+            stream: stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String, ()> {
+        loop {
+            // Line already available in buffer?
+            if let Some(position) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line_bytes: Vec<u8> = self.buffer.drain(..=position).collect();
+
+                return Ok(String::from_utf8_lossy(&line_bytes)
+                    .trim_end_matches(&['\r', '\n'][..])
+                    .to_string());
+            }
+
+            // Prevent servers from feeding endless lines
+            if self.buffer.len() > MAIL_LINE_MAXIMUM_SIZE {
+                return Err(());
+            }
+
+            let mut chunk = [0; 512];
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) | Err(_) => return Err(()),
+                Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+            }
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), ()> {
+        self.stream
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .and_then(|_| self.stream.flush())
+            .or(Err(()))
+    }
+
+    fn into_stream(self) -> Result<S, ()> {
+        // Notice: refuse to upgrade if the server sent data past the STARTTLS response, as this \
+        //   data would otherwise be processed as if it were received over the secure channel \
+        //   (this is a well-known STARTTLS command injection attack vector).
+        if self.buffer.is_empty() == true {
+            Ok(self.stream)
+        } else {
+            Err(())
+        }
+    }
+}
+
+fn converse(protocol: MailProtocol, stream: TcpStream, host: &str, starttls: bool) -> Status {
+    let mut session = MailSession::new(stream);
+
+    // Read server greeting (a missing greeting means the server is dead)
+    let greeting_status = exchange(protocol, &mut session, MailStep::Greeting);
+
+    debug!(
+        "got {} greeting status for host: {}: {:?}",
+        protocol.as_str(),
+        host,
+        greeting_status
+    );
+
+    if let Status::Healthy = greeting_status {
+        if starttls == true {
+            // Notice: SMTP clients must identify themselves using EHLO before they can request \
+            //   STARTTLS (as per RFC 3207), and must identify again once the session is secured.
+            if let MailProtocol::SMTP = protocol {
+                match exchange(protocol, &mut session, MailStep::Capability) {
+                    Status::Healthy => {}
+                    capability_status => return capability_status,
+                }
+            }
+
+            let starttls_status = exchange(protocol, &mut session, MailStep::StartTLS);
+
+            if let Status::Healthy = starttls_status {
+                debug!(
+                    "upgrading {} session to tls for host: {}",
+                    protocol.as_str(),
+                    host
+                );
+
+                match session
+                    .into_stream()
+                    .and_then(|stream| tls_connect(host, stream))
+                {
+                    Ok(secure_stream) => {
+                        converse_capability(protocol, &mut MailSession::new(secure_stream))
+                    }
+                    Err(_) => {
+                        debug!(
+                            "could not upgrade {} session to tls for host: {}",
+                            protocol.as_str(),
+                            host
+                        );
+
+                        Status::Dead
+                    }
+                }
+            } else {
+                starttls_status
+            }
+        } else {
+            converse_capability(protocol, &mut session)
+        }
+    } else {
+        greeting_status
+    }
+}
+
+fn converse_capability<S: Read + Write>(
+    protocol: MailProtocol,
+    session: &mut MailSession<S>,
+) -> Status {
+    let capability_status = exchange(protocol, session, MailStep::Capability);
+
+    // Say goodbye (the result does not matter, as the server already proved to be responsive)
+    exchange(protocol, session, MailStep::Quit);
+
+    capability_status
+}
+
+fn exchange<S: Read + Write>(
+    protocol: MailProtocol,
+    session: &mut MailSession<S>,
+    step: MailStep,
+) -> Status {
+    if let Some(command) = protocol.command(step) {
+        if session.write_line(&command).is_err() {
+            return Status::Dead;
+        }
+    }
+
+    let reply = match protocol {
+        MailProtocol::SMTP => read_reply_smtp(session),
+        MailProtocol::IMAP => read_reply_imap(session, step),
+        MailProtocol::POP3 => read_reply_pop3(session, step),
+    };
+
+    // Consider broken conversations as a failure
+    reply.unwrap_or(Status::Dead)
+}
+
+fn read_reply_smtp<S: Read + Write>(session: &mut MailSession<S>) -> Result<Status, ()> {
+    // Notice: SMTP replies can span over multiple lines, where all lines but the last one have \
+    //   a dash right after the reply code (eg. '250-SIZE', followed by a final '250 HELP').
+    for _ in 0..MAIL_REPLY_MAXIMUM_LINES {
+        let line = session.read_line()?;
+
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(match line.as_bytes().first() {
+                Some(b'2') | Some(b'3') => Status::Healthy,
+                Some(b'4') => Status::Sick,
+                _ => Status::Dead,
+            });
+        }
+    }
+
+    Err(())
+}
+
+fn read_reply_imap<S: Read + Write>(
+    session: &mut MailSession<S>,
+    step: MailStep,
+) -> Result<Status, ()> {
+    let tag = match step {
+        MailStep::Greeting => "*",
+        MailStep::Capability => MAIL_IMAP_TAG_CAPABILITY,
+        MailStep::StartTLS => MAIL_IMAP_TAG_STARTTLS,
+        MailStep::Quit => MAIL_IMAP_TAG_LOGOUT,
+    };
+
+    // Skip untagged responses, until the response tagged with our command tag is received
+    for _ in 0..MAIL_REPLY_MAXIMUM_LINES {
+        let line = session.read_line()?;
+        let mut parts = line.splitn(3, ' ');
+
+        if parts.next() == Some(tag) {
+            return Ok(
+                match parts.next().map(|part| part.to_uppercase()).as_deref() {
+                    Some("OK") | Some("PREAUTH") => Status::Healthy,
+                    Some("NO") => Status::Sick,
+                    _ => Status::Dead,
+                },
+            );
+        }
+    }
+
+    Err(())
+}
+
+fn read_reply_pop3<S: Read + Write>(
+    session: &mut MailSession<S>,
+    step: MailStep,
+) -> Result<Status, ()> {
+    let line = session.read_line()?;
+
+    if line.starts_with("+OK") {
+        // Capability listings are terminated by a single dot line
+        if let MailStep::Capability = step {
+            for _ in 0..MAIL_REPLY_MAXIMUM_LINES {
+                if session.read_line()? == "." {
+                    return Ok(Status::Healthy);
+                }
+            }
+
+            return Err(());
+        }
+
+        Ok(Status::Healthy)
+    } else if line.starts_with("-ERR") {
+        // Notice: POP3 has no reply code classes, though transient errors can be told apart \
+        //   using the response codes from RFC 2449 and RFC 3206 (if the server supports them).
+        let line_upper = line.to_uppercase();
+
+        if line_upper.contains("[SYS/TEMP]")
+            || line_upper.contains("[IN-USE]")
+            || line_upper.contains("[LOGIN-DELAY]")
+        {
+            Ok(Status::Sick)
+        } else {
+            Ok(Status::Dead)
+        }
+    } else {
+        Err(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    type Server = thread::JoinHandle<(Vec<String>, Option<u8>)>;
+
+    // Notice: the server greets, then expects each command in order and answers it with its \
+    //   canned reply, and finally reads the first byte sent past the conversation (if any), so \
+    //   that tests can tell whether a TLS handshake was started.
+    fn serve(greeting: &'static str, replies: Vec<&'static str>) -> (TcpStream, Server) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut commands = Vec::new();
+
+            stream
+                .write_all(format!("{}\r\n", greeting).as_bytes())
+                .unwrap();
+
+            for reply in replies {
+                let mut command = String::new();
+
+                if reader.read_line(&mut command).unwrap() == 0 {
+                    break;
+                }
+
+                commands.push(command.trim_end().to_string());
+
+                stream
+                    .write_all(format!("{}\r\n", reply).as_bytes())
+                    .unwrap();
+            }
+
+            let mut byte = [0; 1];
+
+            let next_byte = match reader.read(&mut byte) {
+                Ok(1) => Some(byte[0]),
+                _ => None,
+            };
+
+            (commands, next_byte)
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        (stream, server)
+    }
+
+    #[test]
+    fn it_converses_smtp() {
+        let (stream, server) = serve(
+            "220 mail.local ESMTP",
+            vec!["250-mail.local\r\n250-SIZE 1000\r\n250 HELP", "221 Bye"],
+        );
+
+        assert_eq!(
+            converse(MailProtocol::SMTP, stream, "mail.local", false),
+            Status::Healthy
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            (vec!["EHLO localhost".to_string(), "QUIT".to_string()], None)
+        );
+
+        let (stream, server) = serve("421 mail.local busy", vec![]);
+
+        assert_eq!(
+            converse(MailProtocol::SMTP, stream, "mail.local", false),
+            Status::Sick
+        );
+        assert_eq!(server.join().unwrap(), (vec![], None));
+    }
+
+    #[test]
+    fn it_converses_imap() {
+        let (stream, server) = serve(
+            "* OK IMAP4rev1 ready",
+            vec![
+                "* CAPABILITY IMAP4rev1 STARTTLS\r\na1 OK done",
+                "* BYE logging out\r\na3 OK done",
+            ],
+        );
+
+        assert_eq!(
+            converse(MailProtocol::IMAP, stream, "mail.local", false),
+            Status::Healthy
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            (
+                vec!["a1 CAPABILITY".to_string(), "a3 LOGOUT".to_string()],
+                None
+            )
+        );
+
+        let (stream, server) = serve("* OK IMAP4rev1 ready", vec!["a1 BAD unknown", "a3 OK"]);
+
+        assert_eq!(
+            converse(MailProtocol::IMAP, stream, "mail.local", false),
+            Status::Dead
+        );
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn it_converses_pop3() {
+        let (stream, server) = serve("+OK POP3 ready", vec!["+OK\r\nTOP\r\nSTLS\r\n.", "+OK bye"]);
+
+        assert_eq!(
+            converse(MailProtocol::POP3, stream, "mail.local", false),
+            Status::Healthy
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            (vec!["CAPA".to_string(), "QUIT".to_string()], None)
+        );
+
+        let (stream, server) = serve("-ERR [SYS/TEMP] too busy", vec![]);
+
+        assert_eq!(
+            converse(MailProtocol::POP3, stream, "mail.local", false),
+            Status::Sick
+        );
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn it_upgrades_to_tls_on_starttls() {
+        let conversations = vec![
+            (
+                MailProtocol::SMTP,
+                "220 mail.local ESMTP",
+                vec!["250 STARTTLS", "220 Ready to start TLS"],
+                vec!["EHLO localhost", "STARTTLS"],
+            ),
+            (
+                MailProtocol::IMAP,
+                "* OK IMAP4rev1 ready",
+                vec!["a2 OK Begin TLS negotiation now"],
+                vec!["a2 STARTTLS"],
+            ),
+            (
+                MailProtocol::POP3,
+                "+OK POP3 ready",
+                vec!["+OK Begin TLS negotiation"],
+                vec!["STLS"],
+            ),
+        ];
+
+        for (protocol, greeting, replies, commands) in conversations {
+            let (stream, server) = serve(greeting, replies);
+
+            // Notice: the server closes the connection upon receiving the TLS handshake
+            assert_eq!(converse(protocol, stream, "mail.local", true), Status::Dead);
+
+            let (server_commands, next_byte) = server.join().unwrap();
+
+            assert_eq!(server_commands, commands);
+
+            // TLS handshake records start with the 'handshake' content type
+            assert_eq!(next_byte, Some(0x16));
+        }
+    }
+
+    #[test]
+    fn it_refuses_failed_or_injected_starttls() {
+        let (stream, server) = serve(
+            "220 mail.local ESMTP",
+            vec!["250 HELP", "454 TLS not available"],
+        );
+
+        assert_eq!(
+            converse(MailProtocol::SMTP, stream, "mail.local", true),
+            Status::Sick
+        );
+        assert_eq!(server.join().unwrap().1, None);
+
+        // Notice: data sent past the STARTTLS reply must abort the upgrade
+        let (stream, server) = serve(
+            "220 mail.local ESMTP",
+            vec!["250 HELP", "220 Ready to start TLS\r\n250 injected"],
+        );
+
+        assert_eq!(
+            converse(MailProtocol::SMTP, stream, "mail.local", true),
+            Status::Dead
+        );
+        assert_eq!(server.join().unwrap().1, None);
+    }
+}
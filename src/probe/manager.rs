@@ -5,21 +5,43 @@
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use super::damping::Damping;
+use super::engine::Engine;
 use super::map::{acquire as map_acquire, Map};
-use super::poll::dispatch as poll_dispatch;
+use super::notify::{
+    ready as notify_ready, sleep as notify_sleep, status as notify_status,
+    watchdog as notify_watchdog,
+};
+use super::poll::{dispatch as poll_dispatch, PollCycleCache};
+use super::report::ReporterClient;
+use super::status::Status;
 
 const PROBE_RUN_HOLD_SECONDS: u64 = 2;
 const PROBE_CHECK_INTERVAL_SECONDS: u64 = 120;
 
-pub fn run() {
-    // Initialize map
-    let mut map = Map {
-        date: None,
-        metrics: None,
-        services: Vec::new(),
-    };
+struct CycleSummary {
+    maps: usize,
+    acquired: usize,
+    healthy: usize,
+    sick: usize,
+    dead: usize,
+}
+
+pub fn run(engine: &Engine, reporters: &[ReporterClient]) {
+    // Initialize maps (one per reporter, as each reporter token has its own probe map)
+    let mut maps: Vec<Map> = reporters
+        .iter()
+        .map(|_| Map {
+            date: None,
+            metrics: None,
+            services: Vec::new(),
+        })
+        .collect();
+
+    // Initialize damping (tracks replica statuses across cycles)
+    let mut damping = Damping::new();
 
     // Hold on a bit before first cycle
     thread::sleep(Duration::from_secs(PROBE_RUN_HOLD_SECONDS));
@@ -28,31 +50,113 @@ pub fn run() {
 
     // Start cycling
     loop {
-        cycle(&mut map);
+        let start_time = Instant::now();
+        let summary = cycle(engine, reporters, &mut maps, &mut damping);
+
+        let status = format!(
+            "last cycle: {}/{} maps acquired, {} replicas ({} healthy, {} sick, {} dead) in {}s",
+            summary.acquired,
+            summary.maps,
+            summary.healthy + summary.sick + summary.dead,
+            summary.healthy,
+            summary.sick,
+            summary.dead,
+            start_time.elapsed().as_secs()
+        );
+
+        // Notify the service manager (the service is ready as soon as a map could be acquired, \
+        //   ie. once the reporter is reachable and its token is valid)
+        if summary.acquired > 0 {
+            notify_ready(status);
+        } else {
+            notify_status(status);
+        }
+
+        notify_watchdog();
 
         info!(
             "done cycling probe, holding for next cycle: {}s",
             PROBE_CHECK_INTERVAL_SECONDS
         );
 
-        // Hold on a bit for next cycle
-        thread::sleep(Duration::from_secs(PROBE_CHECK_INTERVAL_SECONDS));
+        // Hold on a bit for next cycle (while pinging the service manager watchdog)
+        notify_sleep(Duration::from_secs(PROBE_CHECK_INTERVAL_SECONDS));
 
         debug!("holding for next probe cycle, will run next cycle");
     }
 }
 
-fn cycle(map: &mut Map) {
-    // Acquire map changes
-    match map_acquire(map) {
-        Ok(_) => {
-            debug!("acquired map for probe cycle");
+fn cycle(
+    engine: &Engine,
+    reporters: &[ReporterClient],
+    maps: &mut [Map],
+    damping: &mut Damping,
+) -> CycleSummary {
+    // Share probed replica statuses between all maps for this cycle
+    let mut cache = PollCycleCache::new();
+    let mut acquired = 0;
+
+    damping.begin_cycle();
+
+    for (index, (reporter, map)) in reporters.iter().zip(maps.iter_mut()).enumerate() {
+        // Acquire map changes
+        match map_acquire(reporter, map) {
+            Ok(_) => {
+                debug!("acquired map #{} for probe cycle", index);
 
-            // Dispatch polls
-            poll_dispatch(&map.services, &map.metrics, PROBE_CHECK_INTERVAL_SECONDS);
+                acquired += 1;
+
+                // Dispatch polls
+                poll_dispatch(
+                    engine,
+                    reporter,
+                    &map.services,
+                    &map.metrics,
+                    PROBE_CHECK_INTERVAL_SECONDS,
+                    &mut cache,
+                    damping,
+                );
+            }
+            Err(err) => {
+                warn!("probe cycle error in map #{} acquire: {:?}", index, err);
+            }
         }
-        Err(err) => {
-            warn!("probe cycle error in map acquire: {:?}", err);
+
+        // Notice: the service manager watchdog also gets pinged after each map, as acquiring a \
+        //   map may take a while (a stuck cycle would stop pinging it).
+        notify_watchdog();
+    }
+
+    damping.end_cycle();
+
+    let metrics = engine.metrics();
+
+    info!(
+        "probe metrics since start: {} probe failures, {} resolve failures ({} cache hits, {} stale), \
+            {} crashes",
+        metrics.probe_failures,
+        metrics.resolve_failures,
+        metrics.resolve_cache_hits,
+        metrics.resolve_stale,
+        metrics.crashes
+    );
+
+    // Summarize cycle (from unique replicas probed in this cycle)
+    let mut summary = CycleSummary {
+        maps: maps.len(),
+        acquired: acquired,
+        healthy: 0,
+        sick: 0,
+        dead: 0,
+    };
+
+    for status in cache.values() {
+        match status {
+            Status::Healthy => summary.healthy += 1,
+            Status::Sick => summary.sick += 1,
+            Status::Dead => summary.dead += 1,
         }
     }
+
+    summary
 }
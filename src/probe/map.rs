@@ -4,21 +4,19 @@
 // Copyright: 2018, Crisp IM SAS
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-use http_req::{
-    request::{Method, Request},
-    uri::Uri,
-};
+use http_req::{request::Method, uri::Uri};
+use regex::bytes::Regex;
 use serde_json;
 
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::thread;
 use std::time::Duration;
 
+#[cfg(any(feature = "probe-http", feature = "probe-websocket"))]
+use super::json::JSONRule;
 use super::replica::ReplicaURL;
-use super::report::{
-    generate_url as report_generate_url, REPORT_HTTP_CLIENT_TIMEOUT,
-    REPORT_HTTP_HEADER_AUTHORIZATION, REPORT_HTTP_HEADER_USERAGENT,
-};
+use super::report::ReporterClient;
 
 const RETRY_ACQUIRE_TIMES: u8 = 2;
 const RETRY_ACQUIRE_AFTER_SECONDS: u64 = 5;
@@ -35,12 +33,12 @@ pub struct Map {
     pub services: Vec<MapService>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct MapMetrics {
     pub local: MapMetricsLocal,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct MapMetricsLocal {
     pub retry: u8,
     pub delay_dead: u64,
@@ -58,24 +56,87 @@ pub struct MapServiceNode {
     pub id: String,
     pub replicas: Option<Vec<ReplicaURL>>,
     pub http: Option<MapServiceNodeHTTP>,
+    pub websocket: Option<MapServiceNodeWebSocket>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct MapServiceNodeHTTP {
+    pub request: Option<MapServiceNodeHTTPRequest>,
+    pub redirects: Option<MapServiceNodeHTTPRedirects>,
     pub status: Option<MapServiceNodeHTTPStatus>,
     pub body: Option<MapServiceNodeHTTPBody>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
+pub struct MapServiceNodeHTTPRequest {
+    pub method: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+    pub body: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MapServiceNodeHTTPRedirects {
+    pub max_hops: Option<u8>,
+    pub same_host: Option<bool>,
+    pub allow_downgrade: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct MapServiceNodeHTTPStatus {
     pub healthy_above: Option<u16>,
     pub healthy_below: Option<u16>,
+    pub sick_codes: Option<Vec<MapServiceNodeHTTPStatusCodes>>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MapServiceNodeHTTPStatusCodes {
+    Code(u16),
+    Range { above: u16, below: u16 },
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct MapServiceNodeHTTPBody {
     pub healthy_match: Option<String>,
     pub dead_match: Option<String>,
+    pub sick_match: Option<String>,
+    pub healthy_regex: Option<String>,
+    pub dead_regex: Option<String>,
+    pub sick_regex: Option<String>,
+    pub json_rules: Option<Vec<MapServiceNodeHTTPBodyJSONRule>>,
+
+    #[serde(skip)]
+    pub regexes: MapServiceNodeHTTPBodyRegexes,
+}
+
+#[derive(Default)]
+pub struct MapServiceNodeHTTPBodyRegexes {
+    pub healthy: Option<Regex>,
+    pub dead: Option<Regex>,
+    pub sick: Option<Regex>,
+    pub invalid: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MapServiceNodeHTTPBodyJSONRule {
+    pub rule: String,
+    pub failure: Option<MapServiceNodeHTTPBodyJSONRuleFailure>,
+
+    #[cfg(any(feature = "probe-http", feature = "probe-websocket"))]
+    #[serde(skip)]
+    pub parsed: Option<JSONRule>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MapServiceNodeHTTPBodyJSONRuleFailure {
+    Sick,
+    Dead,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MapServiceNodeWebSocket {
+    pub message: Option<String>,
 }
 
 #[derive(Debug)]
@@ -87,15 +148,15 @@ pub enum MapError {
     ExhaustedAttempts,
 }
 
-pub fn acquire(map: &mut Map) -> Result<(), MapError> {
+pub fn acquire(reporter: &ReporterClient, map: &mut Map) -> Result<(), MapError> {
     // Attempt to acquire (first attempt)
-    acquire_attempt(map, 0)
+    acquire_attempt(reporter, map, 0)
 }
 
-fn acquire_attempt(map: &mut Map, attempt: u8) -> Result<(), MapError> {
+fn acquire_attempt(reporter: &ReporterClient, map: &mut Map, attempt: u8) -> Result<(), MapError> {
     info!("running acquire attempt #{}", attempt);
 
-    match acquire_request(map) {
+    match acquire_request(reporter, map) {
         Ok(_) => Ok(()),
         Err(MapError::NotAuthorized) => Err(MapError::NotAuthorized),
         Err(_) => {
@@ -112,13 +173,13 @@ fn acquire_attempt(map: &mut Map, attempt: u8) -> Result<(), MapError> {
                 // Retry after delay
                 thread::sleep(Duration::from_secs(RETRY_ACQUIRE_AFTER_SECONDS));
 
-                acquire_attempt(map, next_attempt)
+                acquire_attempt(reporter, map, next_attempt)
             }
         }
     }
 }
 
-fn acquire_request(map: &mut Map) -> Result<(), MapError> {
+fn acquire_request(reporter: &ReporterClient, map: &mut Map) -> Result<(), MapError> {
     // Generate probe path
     let mut probe_path = String::from("probes/local");
 
@@ -127,23 +188,22 @@ fn acquire_request(map: &mut Map) -> Result<(), MapError> {
         probe_path.push_str(&date.to_string());
     }
 
-    let probe_url = report_generate_url(&probe_path);
+    let probe_url = reporter.generate_url(&probe_path);
 
     debug!("generated probes url: {}", &probe_url);
 
     // Generate request URI
-    let request_uri = Uri::try_from(probe_url.as_str()).expect("invalid probe request uri");
+    let request_uri = Uri::try_from(probe_url.as_str()).map_err(|err| {
+        error!("invalid probe request uri: {} (error: {})", probe_url, err);
+
+        MapError::FailedRequest
+    })?;
 
     // Acquire probe response
     let mut response_body = Vec::new();
 
-    let response = Request::new(&request_uri)
-        .connect_timeout(Some(REPORT_HTTP_CLIENT_TIMEOUT))
-        .read_timeout(Some(REPORT_HTTP_CLIENT_TIMEOUT))
-        .write_timeout(Some(REPORT_HTTP_CLIENT_TIMEOUT))
-        .method(Method::GET)
-        .header("User-Agent", &*REPORT_HTTP_HEADER_USERAGENT)
-        .header("Authorization", &*REPORT_HTTP_HEADER_AUTHORIZATION)
+    let response = reporter
+        .request(&request_uri, Method::GET)
         .send(&mut response_body);
 
     // Acquire items
@@ -176,6 +236,9 @@ fn acquire_request(map: &mut Map) -> Result<(), MapError> {
                                 map.services = response_json.data.services;
                                 map.metrics = response_json.data.metrics;
 
+                                // Compile body regexes and JSON rules (once per acquired map)
+                                compile_rules(&mut map.services);
+
                                 Ok(())
                             }
                             Err(err) => {
@@ -224,3 +287,77 @@ fn acquire_request(map: &mut Map) -> Result<(), MapError> {
         }
     }
 }
+
+pub fn compile_rules(services: &mut [MapService]) {
+    for service in services.iter_mut() {
+        for node in service.nodes.iter_mut() {
+            if let Some(ref mut body) = node.http.as_mut().and_then(|http| http.body.as_mut()) {
+                let (healthy, dead, sick) = (
+                    compile_regex(&service.id, &node.id, "healthy", &body.healthy_regex),
+                    compile_regex(&service.id, &node.id, "dead", &body.dead_regex),
+                    compile_regex(&service.id, &node.id, "sick", &body.sick_regex),
+                );
+
+                body.regexes = MapServiceNodeHTTPBodyRegexes {
+                    invalid: healthy.is_err() == true
+                        || dead.is_err() == true
+                        || sick.is_err() == true,
+                    healthy: healthy.unwrap_or(None),
+                    dead: dead.unwrap_or(None),
+                    sick: sick.unwrap_or(None),
+                };
+
+                #[cfg(any(feature = "probe-http", feature = "probe-websocket"))]
+                if let Some(ref mut json_rules) = body.json_rules {
+                    for json_rule in json_rules.iter_mut() {
+                        json_rule.parsed = parse_json_rule(&service.id, &node.id, &json_rule.rule);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn compile_regex(
+    service_id: &str,
+    node_id: &str,
+    kind: &str,
+    pattern: &Option<String>,
+) -> Result<Option<Regex>, ()> {
+    match pattern {
+        Some(ref pattern_inner) if pattern_inner.is_empty() == false => {
+            match Regex::new(pattern_inner) {
+                Ok(regex) => Ok(Some(regex)),
+                Err(err) => {
+                    // Notice: an invalid regex must not prevent other nodes from being polled, \
+                    //   though it must not be ignored either (as the node could then be reported \
+                    //   healthy without being checked); the node gets reported as dead instead.
+                    error!(
+                        "invalid {} regex for node: #{}:#{}, node will be dead (error: {})",
+                        kind, service_id, node_id, err
+                    );
+
+                    Err(())
+                }
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(any(feature = "probe-http", feature = "probe-websocket"))]
+fn parse_json_rule(service_id: &str, node_id: &str, rule: &str) -> Option<JSONRule> {
+    match JSONRule::parse(rule) {
+        Ok(json_rule) => Some(json_rule),
+        Err(_) => {
+            // Notice: an invalid JSON rule is kept, and always considered as failing, so that a \
+            //   typo in a rule never makes a node look healthy.
+            error!(
+                "invalid json rule for node: #{}:#{}, it will always fail: {}",
+                service_id, node_id, rule
+            );
+
+            None
+        }
+    }
+}
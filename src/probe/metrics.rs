@@ -0,0 +1,57 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Default)]
+pub struct Metrics {
+    probe_failures: AtomicU64,
+    resolve_failures: AtomicU64,
+    resolve_cache_hits: AtomicU64,
+    resolve_stale: AtomicU64,
+    crashes: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsSnapshot {
+    pub probe_failures: u64,
+    pub resolve_failures: u64,
+    pub resolve_cache_hits: u64,
+    pub resolve_stale: u64,
+    pub crashes: u64,
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            probe_failures: self.probe_failures.load(Ordering::Relaxed),
+            resolve_failures: self.resolve_failures.load(Ordering::Relaxed),
+            resolve_cache_hits: self.resolve_cache_hits.load(Ordering::Relaxed),
+            resolve_stale: self.resolve_stale.load(Ordering::Relaxed),
+            crashes: self.crashes.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn count_probe_failure(&self) {
+        self.probe_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_resolve_failure(&self) {
+        self.resolve_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_resolve_cache_hit(&self) {
+        self.resolve_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_resolve_stale(&self) {
+        self.resolve_stale.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_crash(&self) {
+        self.crashes.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -4,9 +4,50 @@
 // Copyright: 2018, Crisp IM SAS
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-mod map;
-mod replica;
-mod status;
+pub mod address;
+pub mod bind;
+#[cfg(any(feature = "probe-http", feature = "probe-websocket"))]
+mod body;
+pub mod damping;
+#[cfg(feature = "probe-http")]
+mod direct;
+#[cfg(feature = "probe-icmp")]
+mod echo;
+pub mod engine;
+#[cfg(feature = "probe-exec")]
+mod exec;
+#[cfg(feature = "probe-grpc")]
+mod grpc;
+#[cfg(feature = "probe-http")]
+mod http;
+#[cfg(feature = "probe-icmp")]
+mod icmp;
+#[cfg(any(feature = "probe-http", feature = "probe-websocket"))]
+mod json;
+#[cfg(feature = "probe-mail")]
+mod mail;
+pub mod map;
+pub mod metrics;
+mod notify;
+pub mod prober;
+pub mod replica;
+pub mod resolver;
+#[cfg(feature = "probe-http")]
+mod secret;
+#[cfg(feature = "probe-ssh")]
+mod ssh;
+pub mod status;
+#[cfg(feature = "probe-tcp")]
+mod tcp;
+#[cfg(any(
+    feature = "probe-http",
+    feature = "probe-mail",
+    feature = "probe-grpc",
+    feature = "probe-websocket"
+))]
+mod tls;
+#[cfg(feature = "probe-websocket")]
+mod websocket;
 
 pub mod manager;
 pub mod poll;
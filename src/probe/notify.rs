@@ -0,0 +1,186 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static NOTIFY_READY: AtomicBool = AtomicBool::new(false);
+
+pub fn ready<S: AsRef<str>>(status: S) {
+    // Notice: the service is only reported as ready once, as the probe thread may get set up \
+    //   again after a crash (which does not make the service ready again).
+    if NOTIFY_READY.swap(true, Ordering::Relaxed) == false {
+        send(&format!("READY=1\nSTATUS={}", status.as_ref()));
+    } else {
+        self::status(status);
+    }
+}
+
+pub fn status<S: AsRef<str>>(status: S) {
+    send(&format!("STATUS={}", status.as_ref()));
+}
+
+pub fn watchdog() {
+    if watchdog_interval().is_some() {
+        send("WATCHDOG=1");
+    }
+}
+
+pub fn sleep(duration: Duration) {
+    // Sleep in chunks, and ping the watchdog between each chunk? (if enabled)
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return thread::sleep(duration),
+    };
+
+    let start_time = Instant::now();
+
+    while let Some(remaining) = duration.checked_sub(start_time.elapsed()) {
+        if remaining == Duration::ZERO {
+            break;
+        }
+
+        thread::sleep(remaining.min(interval));
+
+        send("WATCHDOG=1");
+    }
+}
+
+fn watchdog_interval() -> Option<Duration> {
+    // Notice: the watchdog is enabled by the service manager, which expects pings at least \
+    //   once every 'WATCHDOG_USEC' microseconds; pings are sent twice as often for safety.
+    let interval = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+
+    // Watchdog enabled for another process? (eg. the parent shell)
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(process::id()) {
+            return None;
+        }
+    }
+
+    if interval > 0 {
+        Some(Duration::from_micros(interval / 2))
+    } else {
+        None
+    }
+}
+
+fn send(state: &str) {
+    // Not running under a service manager that supports notifications? (nothing to do)
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) if path.is_empty() == false => path,
+        _ => return,
+    };
+
+    debug!("sending service manager notification: {:?}", state);
+
+    if let Err(err) = send_to(&path, state) {
+        warn!(
+            "failed sending service manager notification to: {} (error: {})",
+            path, err
+        );
+    }
+}
+
+fn send_to(path: &str, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    // Notice: socket paths starting with '@' are in the Linux abstract namespace
+    match path.strip_prefix('@') {
+        Some(name) => send_abstract(&socket, name, state),
+        None => socket.send_to(state.as_bytes(), path).map(|_| ()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &str, state: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let address = SocketAddr::from_abstract_name(name.as_bytes())?;
+
+    socket.send_to_addr(state.as_bytes(), &address).map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_socket: &UnixDatagram, name: &str, _state: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot use abstract socket: @{} on this platform", name),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn it_notifies_service_manager() {
+        let directory =
+            env::temp_dir().join(format!("crisp-status-local-notify-{}", process::id()));
+        let path = directory.join("notify.sock");
+
+        fs::create_dir_all(&directory).unwrap();
+
+        let _ = fs::remove_file(&path);
+
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        // Notice: this is the only test that sets the service manager environment, as it is \
+        //   shared by all tests of the process.
+        env::set_var("NOTIFY_SOCKET", &path);
+        env::set_var("WATCHDOG_USEC", "100000");
+        env::set_var("WATCHDOG_PID", process::id().to_string());
+
+        let receive = || {
+            let mut buffer = [0; 256];
+            let size = socket.recv(&mut buffer).unwrap();
+
+            String::from_utf8_lossy(&buffer[..size]).to_string()
+        };
+
+        // The service is only reported as ready once
+        ready("first");
+        ready("second");
+
+        assert_eq!(receive(), "READY=1\nSTATUS=first");
+        assert_eq!(receive(), "STATUS=second");
+
+        watchdog();
+
+        assert_eq!(receive(), "WATCHDOG=1");
+
+        // The watchdog gets pinged every half watchdog interval while sleeping
+        sleep(Duration::from_millis(120));
+
+        assert_eq!(receive(), "WATCHDOG=1");
+        assert_eq!(receive(), "WATCHDOG=1");
+        assert_eq!(receive(), "WATCHDOG=1");
+
+        // Watchdog enabled for another process? (no ping is sent)
+        env::set_var("WATCHDOG_PID", "1");
+
+        watchdog();
+        status("done");
+
+        assert_eq!(receive(), "STATUS=done");
+
+        env::remove_var("NOTIFY_SOCKET");
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
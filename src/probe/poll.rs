@@ -4,36 +4,47 @@
 // Copyright: 2018, Crisp IM SAS
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-use fastping_rs::{PingResult, Pinger};
-use http_req::{
-    request::{Method, Request},
-    uri::Uri,
-};
-use memmem::{Searcher, TwoWaySearcher};
+use serde_json;
 
-use std::cmp::min;
-use std::convert::TryFrom;
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
 use std::time::Duration;
 use std::time::SystemTime;
 
-use super::map::{MapMetrics, MapService, MapServiceNodeHTTP};
+use super::damping::Damping;
+use super::engine::Engine;
+use super::map::{
+    MapMetrics, MapService, MapServiceNode, MapServiceNodeHTTP, MapServiceNodeWebSocket,
+};
+use super::notify::watchdog as notify_watchdog;
+use super::prober::ProbeOutcome;
 use super::replica::ReplicaURL;
-use super::report::status as report_status;
+use super::report::{status as report_status, ReporterClient};
 use super::status::Status;
 
-const NODE_ICMP_TIMEOUT_MILLISECONDS: u64 = 1000;
-const NODE_HTTP_HEALTHY_ABOVE: u16 = 200;
-const NODE_HTTP_HEALTHY_BELOW: u16 = 400;
 const RETRY_REPLICA_AFTER_MILLISECONDS: u64 = 200;
 
-lazy_static! {
-    static ref POLL_HTTP_HEADER_USERAGENT: String =
-        format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+pub type PollCycleCache = HashMap<String, Status>;
+
+#[derive(Serialize)]
+struct PollCycleCacheKey<'a> {
+    replica: &'a str,
+    http: &'a Option<MapServiceNodeHTTP>,
+    websocket: &'a Option<MapServiceNodeWebSocket>,
+    metrics: &'a Option<MapMetrics>,
 }
 
-pub fn dispatch(services: &Vec<MapService>, metrics: &Option<MapMetrics>, interval: u64) {
+pub fn dispatch(
+    engine: &Engine,
+    reporter: &ReporterClient,
+    services: &Vec<MapService>,
+    metrics: &Option<MapMetrics>,
+    interval: u64,
+    cache: &mut PollCycleCache,
+    damping: &mut Damping,
+) {
     debug!("will dispatch polls");
 
     for service in services {
@@ -44,15 +55,57 @@ pub fn dispatch(services: &Vec<MapService>, metrics: &Option<MapMetrics>, interv
 
             if let Some(ref replicas) = node.replicas {
                 for replica in replicas {
-                    let replica_status =
-                        proceed_replica(&service.id, &node.id, replica, &node.http, metrics);
+                    // Notice: identical replica probes (ie. same replica and same probe \
+                    //   settings) may be shared by multiple status pages, in which case they \
+                    //   get probed once per cycle, and their status is reported to each page.
+                    let cache_key = serde_json::to_string(&PollCycleCacheKey {
+                        replica: replica.get_raw(),
+                        http: &node.http,
+                        websocket: &node.websocket,
+                        metrics: metrics,
+                    })
+                    .unwrap_or_else(|_| replica.get_raw().to_owned());
+
+                    let replica_status = match cache.get(&cache_key) {
+                        Some(replica_status) => {
+                            debug!(
+                                "re-using replica status probed earlier in cycle: {}",
+                                replica.get_raw()
+                            );
+
+                            *replica_status
+                        }
+                        None => {
+                            let replica_status =
+                                proceed_replica(engine, &service.id, node, replica, metrics);
+
+                            // Damp status changes (this prevents flapping replicas from \
+                            //   spamming the status page)
+                            let replica_status = damping.apply(
+                                &engine.damping,
+                                &cache_key,
+                                replica.get_raw(),
+                                replica_status,
+                            );
+
+                            cache.insert(cache_key, replica_status);
+
+                            replica_status
+                        }
+                    };
 
                     debug!("got replica status upon poll: {:?}", replica_status);
 
-                    match report_status(service, node, replica, &replica_status, interval) {
+                    match report_status(reporter, service, node, replica, &replica_status, interval)
+                    {
                         Ok(_) => info!("reported replica status: {:?}", replica_status),
                         Err(_) => warn!("failed reporting replica status: {:?}", replica_status),
                     }
+
+                    // Notice: a map may hold many replicas (each of them taking up to its dead \
+                    //   delay times its retries), so the service manager watchdog gets pinged \
+                    //   after each replica, rather than only after each map.
+                    notify_watchdog();
                 }
             }
         }
@@ -62,38 +115,41 @@ pub fn dispatch(services: &Vec<MapService>, metrics: &Option<MapMetrics>, interv
 }
 
 pub fn proceed_replica(
+    engine: &Engine,
     service_id: &str,
-    node_id: &str,
+    node: &MapServiceNode,
     replica: &ReplicaURL,
-    http: &Option<MapServiceNodeHTTP>,
     metrics: &Option<MapMetrics>,
 ) -> Status {
-    // Acquire number of times to retry
-    let retry_times = if let &Some(ref metrics_inner) = metrics {
+    // Acquire number of times to retry (invalid replicas are never retried, as they would fail \
+    //   again anyway)
+    let retry_times = if replica.is_valid() == false {
+        0
+    } else if let &Some(ref metrics_inner) = metrics {
         metrics_inner.local.retry
     } else {
         2
     };
 
     // Attempt to acquire (first attempt)
-    proceed_replica_attempt(service_id, node_id, replica, http, metrics, retry_times, 0)
+    proceed_replica_attempt(engine, service_id, node, replica, metrics, retry_times, 0)
 }
 
 fn proceed_replica_attempt(
+    engine: &Engine,
     service_id: &str,
-    node_id: &str,
+    node: &MapServiceNode,
     replica: &ReplicaURL,
-    http: &Option<MapServiceNodeHTTP>,
     metrics: &Option<MapMetrics>,
     retry_times: u8,
     attempt: u8,
 ) -> Status {
     info!(
         "running replica scan attempt #{} on #{}:#{}:[{:?}]",
-        attempt, service_id, node_id, replica
+        attempt, service_id, node.id, replica
     );
 
-    match proceed_replica_request(service_id, node_id, replica, http, metrics) {
+    match proceed_replica_request(engine, service_id, node, replica, metrics) {
         Status::Healthy => Status::Healthy,
         Status::Sick => Status::Sick,
         Status::Dead => {
@@ -104,17 +160,17 @@ fn proceed_replica_attempt(
             } else {
                 warn!(
                     "replica scan attempt #{} failed on #{}:#{}:[{:?}], will retry after delay",
-                    attempt, service_id, node_id, replica
+                    attempt, service_id, node.id, replica
                 );
 
                 // Retry after delay
                 thread::sleep(Duration::from_millis(RETRY_REPLICA_AFTER_MILLISECONDS));
 
                 proceed_replica_attempt(
+                    engine,
                     service_id,
-                    node_id,
+                    node,
                     replica,
-                    http,
                     metrics,
                     retry_times,
                     next_attempt,
@@ -125,31 +181,67 @@ fn proceed_replica_attempt(
 }
 
 fn proceed_replica_request(
+    engine: &Engine,
     service_id: &str,
-    node_id: &str,
+    node: &MapServiceNode,
     replica: &ReplicaURL,
-    http: &Option<MapServiceNodeHTTP>,
     metrics: &Option<MapMetrics>,
 ) -> Status {
     debug!(
         "scanning replica: #{}:#{}:[{:?}]",
-        service_id, node_id, replica
+        service_id, node.id, replica
     );
 
     let start_time = SystemTime::now();
 
-    let (is_up, poll_duration) = match replica {
-        &ReplicaURL::ICMP(_, ref host) => proceed_replica_request_icmp(host, metrics),
-        &ReplicaURL::TCP(_, ref host, port) => proceed_replica_request_tcp(host, port, metrics),
-        &ReplicaURL::HTTP(_, ref url) => proceed_replica_request_http(url, http, metrics),
-        &ReplicaURL::HTTPS(_, ref url) => proceed_replica_request_http(url, http, metrics),
-    };
+    // Notice: a panicking probe must not abort the whole poll cycle (and lose the acquired \
+    //   map), so panics are caught here, and reported as a dead replica.
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| replica.probe(engine, node, metrics)))
+        .unwrap_or_else(|payload| {
+            error!(
+                "replica: #{}:#{}:[{}] probe panicked: {}",
+                service_id,
+                node.id,
+                replica.get_raw(),
+                panic_reason(&*payload)
+            );
+
+            ProbeOutcome::dead(format!("probe panicked: {}", panic_reason(&*payload)))
+        });
 
-    if is_up == true {
+    if let Some(ref reason) = outcome.reason {
+        debug!(
+            "replica: #{}:#{}:[{}] reported as {:?} (reason: {})",
+            service_id,
+            node.id,
+            replica.get_raw(),
+            outcome.status,
+            reason
+        );
+    }
+
+    // Account for failures (resolution failures are accounted for separately, as they are \
+    //   likely caused by the resolver rather than by the replica)
+    if let Status::Dead = outcome.status {
+        if outcome.unresolved == true {
+            warn!(
+                "replica: #{}:#{}:[{}] could not be resolved",
+                service_id,
+                node.id,
+                replica.get_raw()
+            );
+
+            engine.metrics.count_resolve_failure();
+        } else {
+            engine.metrics.count_probe_failure();
+        }
+    }
+
+    if let Status::Healthy = outcome.status {
         // Probe reports as sick?
         if let &Some(ref metrics_inner) = metrics {
             // Acquire poll duration latency
-            let duration_latency = match poll_duration {
+            let duration_latency = match outcome.latency {
                 Some(poll_duration) => poll_duration,
                 None => SystemTime::now()
                     .duration_since(start_time)
@@ -160,285 +252,19 @@ fn proceed_replica_request(
                 return Status::Sick;
             }
         }
-
-        Status::Healthy
-    } else {
-        Status::Dead
-    }
-}
-
-fn proceed_replica_request_icmp(
-    host: &str,
-    metrics: &Option<MapMetrics>,
-) -> (bool, Option<Duration>) {
-    // Notice: a dummy port of value '0' is set here, so that we can resolve the host to an actual \
-    //   IP address using the standard library, which avoids depending on an additional library.
-    let address_results = (host, 0).to_socket_addrs();
-
-    // Storage variable for the maximum round-trip-time found for received ping responses
-    let mut maximum_rtt = None;
-
-    match address_results {
-        Ok(address) => {
-            // Notice: the ICMP probe checker is a bit special, in the sense that it checks all \
-            //   resolved addresses. As we check for an host health at the IP level (ie. not at \
-            //   the application layer level), checking only the first host in the list is not \
-            //   sufficient for the whole replica group to be up. This can be used as an handy way \
-            //   to check for the health of a group of IP hosts, configured in a single DNS record.
-            let address_values: Vec<SocketAddr> = address.collect();
-
-            if !address_values.is_empty() {
-                debug!(
-                    "prober poll will fire for icmp host: {} ({} targets)",
-                    host,
-                    address_values.len()
-                );
-
-                // As ICMP pings require a lower-than-usual timeout, an hard-coded ICMP \
-                //   timeout value is used by default, though the configured dead delay value \
-                //   is preferred in the event it is lower than the hard-coded value (unlikely \
-                //   though possible in some setups).
-                let pinger_timeout = min(
-                    NODE_ICMP_TIMEOUT_MILLISECONDS,
-                    acquire_dead_timeout(metrics).as_secs() * 1000,
-                );
-
-                let (pinger, results) =
-                    Pinger::new(Some(pinger_timeout), None).expect("failed to create icmp pinger");
-
-                // Probe all returned addresses (sequentially)
-                for address_value in &address_values {
-                    let address_ip = address_value.ip();
-
-                    debug!(
-                        "prober poll will send icmp ping to target: {} from host: {}",
-                        address_ip, host
-                    );
-
-                    pinger.add_ipaddr(&address_ip.to_string());
-                }
-
-                pinger.ping_once();
-
-                for _ in &address_values {
-                    match results.recv() {
-                        Ok(result) => match result {
-                            PingResult::Receive { addr, rtt } => {
-                                debug!(
-                                    "got prober poll result for icmp target: {} from host: {}",
-                                    addr, host
-                                );
-
-                                // Do not return (consider address as reachable)
-                                // Notice: update maximum observed round-trip-time, if higher than \
-                                //   last highest observed.
-                                maximum_rtt = match maximum_rtt {
-                                    Some(maximum_rtt) => {
-                                        if rtt > maximum_rtt {
-                                            Some(rtt)
-                                        } else {
-                                            Some(maximum_rtt)
-                                        }
-                                    }
-                                    None => Some(rtt),
-                                };
-                            }
-                            PingResult::Idle { addr } => {
-                                debug!(
-                                    "prober poll host idle for icmp target: {} from host: {}",
-                                    addr, host
-                                );
-
-                                // Consider ICMP idle hosts as a failure (ie. routable, but \
-                                //   unreachable)
-                                return (false, None);
-                            }
-                        },
-                        Err(err) => {
-                            debug!("prober poll error for icmp host: {} (error: {})", host, err);
-
-                            // Consider ICMP errors as a failure
-                            return (false, None);
-                        }
-                    };
-                }
-            } else {
-                debug!(
-                    "prober poll did not resolve any address for icmp replica: {}",
-                    host
-                );
-
-                // Consider empty as a failure
-                return (false, None);
-            }
-        }
-        Err(err) => {
-            error!(
-                "prober poll address for icmp replica is invalid: {} (error: {})",
-                host, err
-            );
-
-            // Consider invalid URL as a failure
-            return (false, None);
-        }
-    };
-
-    // If there was no early return, consider all the hosts as reachable for replica
-    (true, maximum_rtt)
-}
-
-fn proceed_replica_request_tcp(
-    host: &str,
-    port: u16,
-    metrics: &Option<MapMetrics>,
-) -> (bool, Option<Duration>) {
-    let address_results = (host, port).to_socket_addrs();
-
-    if let Ok(mut address) = address_results {
-        if let Some(address_value) = address.next() {
-            debug!("prober poll will fire for tcp target: {}", address_value);
-
-            return match TcpStream::connect_timeout(&address_value, acquire_dead_timeout(metrics)) {
-                Ok(_) => (true, None),
-                Err(_) => (false, None),
-            };
-        }
     }
 
-    (false, None)
+    outcome.status
 }
 
-fn proceed_replica_request_http(
-    url: &str,
-    http: &Option<MapServiceNodeHTTP>,
-    metrics: &Option<MapMetrics>,
-) -> (bool, Option<Duration>) {
-    debug!("prober poll will fire for http target: {}", &url);
-
-    // Unpack HTTP body match
-    let (mut http_body_healthy_match, mut http_body_dead_match) = (None, None);
-
-    if let Some(ref http_inner) = http {
-        if let Some(ref http_inner_body_inner) = http_inner.body {
-            if let Some(ref healthy_match_inner) = http_inner_body_inner.healthy_match {
-                if healthy_match_inner.is_empty() == false {
-                    http_body_healthy_match = Some(healthy_match_inner.to_owned());
-                }
-            }
-
-            if let Some(ref dead_match_inner) = http_inner_body_inner.dead_match {
-                if dead_match_inner.is_empty() == false {
-                    http_body_dead_match = Some(dead_match_inner.to_owned());
-                }
-            }
-        }
-    }
-
-    // Unpack dead timeout
-    let dead_timeout = acquire_dead_timeout(metrics);
-
-    // Generate request URI
-    let request_uri = Uri::try_from(url).expect("invalid replica request uri");
-
-    // Acquire replica response
-    let mut response_body = Vec::new();
-
-    let response = Request::new(&request_uri)
-        .connect_timeout(Some(dead_timeout))
-        .read_timeout(Some(dead_timeout))
-        .write_timeout(Some(dead_timeout))
-        .method(
-            if http_body_healthy_match.is_some() == true || http_body_dead_match.is_some() == true {
-                Method::GET
-            } else {
-                Method::HEAD
-            },
-        )
-        .header("User-Agent", &*POLL_HTTP_HEADER_USERAGENT)
-        .send(&mut response_body);
-
-    // Handle response
-    if let Ok(response) = response {
-        let status_code = u16::from(response.status_code());
-
-        debug!(
-            "prober poll result received for url: {} with status: {}",
-            &url, status_code
-        );
-
-        // Unpack HTTP status codes
-        let mut http_healthy_above = NODE_HTTP_HEALTHY_ABOVE;
-        let mut http_healthy_below = NODE_HTTP_HEALTHY_BELOW;
-
-        if let &Some(ref http_inner) = http {
-            if let Some(ref http_inner_status_inner) = http_inner.status {
-                if let Some(healthy_above_inner) = http_inner_status_inner.healthy_above {
-                    http_healthy_above = healthy_above_inner;
-                }
-
-                if let Some(healthy_below_inner) = http_inner_status_inner.healthy_below {
-                    http_healthy_below = healthy_below_inner;
-                }
-            }
-        }
-
-        // Consider as UP?
-        if status_code >= http_healthy_above && status_code < http_healthy_below {
-            // Check response body for dead match? (if configured)
-            if let Some(ref http_body_dead_match_inner) = http_body_dead_match {
-                if !response_body.is_empty() {
-                    debug!(
-                        "checking prober poll result response text for url: {} for dead match",
-                        &url
-                    );
-
-                    // Matches? Consider as DOWN.
-                    let text_search = TwoWaySearcher::new(http_body_dead_match_inner.as_bytes())
-                        .search_in(&response_body);
-
-                    if text_search.is_some() {
-                        return (false, None);
-                    }
-                }
-            }
-
-            // Check response body for healthy match? (if configured)
-            if let Some(ref http_body_healthy_match_inner) = http_body_healthy_match {
-                if !response_body.is_empty() {
-                    debug!(
-                        "checking prober poll result response text for url: {} for healthy match",
-                        &url
-                    );
-
-                    // Doesnt match? Consider as DOWN.
-                    let text_search = TwoWaySearcher::new(http_body_healthy_match_inner.as_bytes())
-                        .search_in(&response_body);
-
-                    if text_search.is_none() {
-                        return (false, None);
-                    }
-                } else {
-                    debug!("could not unpack response text for url: {}", &url);
-
-                    // Consider as DOWN (the response text could not be checked)
-                    return (false, None);
-                }
-            }
-
-            return (true, None);
-        }
+pub fn panic_reason(payload: &(dyn Any + Send)) -> String {
+    // Notice: panic payloads are either static strings or formatted strings (as raised with \
+    //   the 'panic!()' and 'expect()' macros), other payload types cannot be displayed.
+    if let Some(reason) = payload.downcast_ref::<&str>() {
+        (*reason).to_owned()
+    } else if let Some(reason) = payload.downcast_ref::<String>() {
+        reason.to_owned()
     } else {
-        debug!("prober poll result was not received for url: {}", &url);
+        "unknown panic".to_owned()
     }
-
-    // Consider as DOWN.
-    (false, None)
-}
-
-fn acquire_dead_timeout(metrics: &Option<MapMetrics>) -> Duration {
-    Duration::from_secs(if let &Some(ref metrics_inner) = metrics {
-        metrics_inner.local.delay_dead
-    } else {
-        20
-    })
 }
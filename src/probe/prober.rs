@@ -0,0 +1,217 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use url::{form_urlencoded, Host, Url};
+
+use std::collections::BTreeMap;
+use std::fmt;
+#[cfg(any(feature = "probe-icmp", feature = "probe-mail", feature = "probe-ssh"))]
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::engine::Engine;
+use super::map::{MapMetrics, MapServiceNode};
+use super::status::Status;
+
+#[cfg(feature = "probe-exec")]
+use super::exec::ExecProber;
+#[cfg(feature = "probe-grpc")]
+use super::grpc::GRPCProber;
+#[cfg(feature = "probe-http")]
+use super::http::HTTPProber;
+#[cfg(feature = "probe-icmp")]
+use super::icmp::ICMPProber;
+#[cfg(feature = "probe-mail")]
+use super::mail::MailProber;
+#[cfg(feature = "probe-ssh")]
+use super::ssh::SSHProber;
+#[cfg(feature = "probe-tcp")]
+use super::tcp::TCPProber;
+#[cfg(feature = "probe-websocket")]
+use super::websocket::WebSocketProber;
+
+type ProberParser = fn(&Url) -> Result<Box<dyn Prober>, ()>;
+
+lazy_static! {
+    static ref PROBER_REGISTRY: BTreeMap<&'static str, ProberParser> = make_registry();
+}
+
+pub(crate) trait Prober: fmt::Debug + Send + Sync {
+    #[cfg(feature = "probe-any")]
+    fn parse(url: &Url) -> Result<Self, ()>
+    where
+        Self: Sized;
+
+    fn probe(
+        &self,
+        engine: &Engine,
+        node: &MapServiceNode,
+        metrics: &Option<MapMetrics>,
+    ) -> ProbeOutcome;
+}
+
+#[derive(Debug)]
+pub struct ProbeOutcome {
+    pub status: Status,
+    pub latency: Option<Duration>,
+    pub reason: Option<String>,
+    pub unresolved: bool,
+}
+
+impl ProbeOutcome {
+    pub fn from_status<R: Into<String>>(status: Status, reason: R) -> ProbeOutcome {
+        // Notice: healthy outcomes do not carry any reason
+        match status {
+            Status::Healthy => Self::healthy(),
+            status => ProbeOutcome {
+                status: status,
+                latency: None,
+                reason: Some(reason.into()),
+                unresolved: false,
+            },
+        }
+    }
+
+    pub fn healthy() -> ProbeOutcome {
+        ProbeOutcome {
+            status: Status::Healthy,
+            latency: None,
+            reason: None,
+            unresolved: false,
+        }
+    }
+
+    pub fn sick<R: Into<String>>(reason: R) -> ProbeOutcome {
+        ProbeOutcome {
+            status: Status::Sick,
+            latency: None,
+            reason: Some(reason.into()),
+            unresolved: false,
+        }
+    }
+
+    pub fn dead<R: Into<String>>(reason: R) -> ProbeOutcome {
+        ProbeOutcome {
+            status: Status::Dead,
+            latency: None,
+            reason: Some(reason.into()),
+            unresolved: false,
+        }
+    }
+
+    pub fn unresolved<E: fmt::Display>(err: E) -> ProbeOutcome {
+        // Notice: resolution failures are told apart from other failures, as they are likely \
+        //   caused by the resolver rather than by the replica itself.
+        ProbeOutcome {
+            unresolved: true,
+            ..Self::dead(format!("resolution failed: {}", err))
+        }
+    }
+}
+
+pub(crate) fn parse(url: &Url) -> Result<Box<dyn Prober>, ()> {
+    match PROBER_REGISTRY.get(url.scheme()) {
+        Some(parser) => parser(url),
+        None => Err(()),
+    }
+}
+
+pub fn schemes() -> Vec<&'static str> {
+    PROBER_REGISTRY.keys().cloned().collect()
+}
+
+pub fn acquire_dead_timeout(metrics: &Option<MapMetrics>) -> Duration {
+    Duration::from_secs(if let &Some(ref metrics_inner) = metrics {
+        metrics_inner.local.delay_dead
+    } else {
+        20
+    })
+}
+
+pub fn host_string(host: Host<&str>) -> String {
+    // Convert internal host value into string. This is especially useful for IPv6 addresses, \
+    //   which we need returned in '::1' format; as they would otherwise be returned in \
+    //   '[::1]' format using built-in top-level 'to_string()' method on the 'Host' trait. The \
+    //   underlying address parser does not accept IPv6 addresses formatted as '[::1]', so \
+    //   this seemingly overkill processing is obviously needed.
+    match host {
+        Host::Domain(domain_value) => domain_value.to_string(),
+        Host::Ipv4(ipv4_value) => ipv4_value.to_string(),
+        Host::Ipv6(ipv6_value) => ipv6_value.to_string(),
+    }
+}
+
+pub fn options(url: &Url) -> Vec<(String, String)> {
+    // Notice: replica options are passed in the URL fragment (eg. \
+    //   'tcp://db.local:5432#addresses=all'), as fragments are never sent to probed servers, \
+    //   and therefore cannot conflict with query parameters.
+    url.fragment()
+        .map(|fragment| {
+            form_urlencoded::parse(fragment.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(any(feature = "probe-icmp", feature = "probe-mail", feature = "probe-ssh"))]
+pub(crate) fn option<T: FromStr>(options: &[(String, String)], key: &str) -> Result<Option<T>, ()> {
+    match options.iter().find(|(option_key, _)| option_key == key) {
+        Some((_, value)) => value.parse().map(Some).or(Err(())),
+        None => Ok(None),
+    }
+}
+
+fn make_registry() -> BTreeMap<&'static str, ProberParser> {
+    // Notice: each probe kind can be disabled at build time using cargo features, which helps \
+    //   in keeping the binary small (eg. when building for embedded targets).
+    #[allow(unused_mut)]
+    let mut registry: BTreeMap<&'static str, ProberParser> = BTreeMap::new();
+
+    #[cfg(feature = "probe-icmp")]
+    registry.insert("icmp", parse_as::<ICMPProber>);
+
+    #[cfg(feature = "probe-tcp")]
+    registry.insert("tcp", parse_as::<TCPProber>);
+
+    #[cfg(feature = "probe-http")]
+    {
+        registry.insert("http", parse_as::<HTTPProber>);
+        registry.insert("https", parse_as::<HTTPProber>);
+    }
+
+    #[cfg(feature = "probe-mail")]
+    {
+        registry.insert("smtp", parse_as::<MailProber>);
+        registry.insert("imap", parse_as::<MailProber>);
+        registry.insert("pop3", parse_as::<MailProber>);
+    }
+
+    #[cfg(feature = "probe-grpc")]
+    {
+        registry.insert("grpc", parse_as::<GRPCProber>);
+        registry.insert("grpcs", parse_as::<GRPCProber>);
+    }
+
+    #[cfg(feature = "probe-websocket")]
+    {
+        registry.insert("ws", parse_as::<WebSocketProber>);
+        registry.insert("wss", parse_as::<WebSocketProber>);
+    }
+
+    #[cfg(feature = "probe-ssh")]
+    registry.insert("ssh", parse_as::<SSHProber>);
+
+    #[cfg(feature = "probe-exec")]
+    registry.insert("exec", parse_as::<ExecProber>);
+
+    registry
+}
+
+#[cfg(feature = "probe-any")]
+fn parse_as<P: Prober + 'static>(url: &Url) -> Result<Box<dyn Prober>, ()> {
+    P::parse(url).map(|prober| Box::new(prober) as Box<dyn Prober>)
+}
@@ -8,62 +8,70 @@ use std::fmt;
 
 use serde::de::{Error, Visitor};
 use serde::{Deserialize, Deserializer};
-use url::{Host, Url};
-
-#[derive(Serialize, Debug, Clone)]
-pub enum ReplicaURL {
-    ICMP(String, String),
-    TCP(String, String, u16),
-    HTTP(String, String),
-    HTTPS(String, String),
+use url::Url;
+
+use super::engine::{Engine, EngineError};
+use super::map::{MapMetrics, MapServiceNode};
+use super::prober::{parse as prober_parse, schemes as prober_schemes, ProbeOutcome, Prober};
+
+#[derive(Debug)]
+pub struct ReplicaURL {
+    raw_url: String,
+    prober: Result<Box<dyn Prober>, &'static str>,
 }
 
 impl ReplicaURL {
-    pub fn parse_from(raw_url: &str) -> Result<ReplicaURL, ()> {
-        match Url::parse(raw_url) {
-            Ok(url) => match url.scheme() {
-                "icmp" => match url.host() {
-                    Some(host) => Ok(ReplicaURL::ICMP(
-                        raw_url.to_owned(),
-                        Self::host_string(host),
-                    )),
-                    _ => Err(()),
-                },
-                "tcp" => match (url.host(), url.port()) {
-                    (Some(host), Some(port)) => Ok(ReplicaURL::TCP(
-                        raw_url.to_owned(),
-                        Self::host_string(host),
-                        port,
-                    )),
-                    _ => Err(()),
-                },
-                "http" => Ok(ReplicaURL::HTTP(raw_url.to_owned(), url.to_string())),
-                "https" => Ok(ReplicaURL::HTTPS(raw_url.to_owned(), url.to_string())),
-                _ => Err(()),
-            },
-            _ => Err(()),
+    pub fn parse_from(raw_url: &str) -> Result<ReplicaURL, EngineError> {
+        let replica = Self::parse_lenient(raw_url);
+
+        if replica.prober.is_ok() == true {
+            Ok(replica)
+        } else {
+            Err(EngineError::InvalidReplica)
         }
     }
 
-    pub fn get_raw(&self) -> &str {
-        match self {
-            &ReplicaURL::ICMP(ref raw_url, _) => raw_url,
-            &ReplicaURL::TCP(ref raw_url, _, _) => raw_url,
-            &ReplicaURL::HTTP(ref raw_url, _) => raw_url,
-            &ReplicaURL::HTTPS(ref raw_url, _) => raw_url,
+    fn parse_lenient(raw_url: &str) -> ReplicaURL {
+        // Notice: replicas that cannot be parsed (eg. with a scheme whose probe was not built, \
+        //   or with invalid options) are kept, and always reported as dead, so that a single \
+        //   invalid replica does not prevent the rest of the map from being probed.
+        let prober = Url::parse(raw_url)
+            .or(Err("invalid replica url"))
+            .and_then(|url| {
+                if prober_schemes().contains(&url.scheme()) == false {
+                    Err("unsupported replica scheme")
+                } else {
+                    prober_parse(&url).or(Err("invalid replica url or options"))
+                }
+            });
+
+        if let Err(reason) = prober {
+            error!("{}: {}, it will be reported as dead", reason, raw_url);
+        }
+
+        ReplicaURL {
+            raw_url: raw_url.to_owned(),
+            prober: prober,
         }
     }
 
-    fn host_string(host: Host<&str>) -> String {
-        // Convert internal host value into string. This is especially useful for IPv6 addresses, \
-        //   which we need returned in '::1' format; as they would otherwise be returned in \
-        //   '[::1]' format using built-in top-level 'to_string()' method on the 'Host' trait. The \
-        //   underlying address parser does not accept IPv6 addresses formatted as '[::1]', so \
-        //   this seemingly overkill processing is obviously needed.
-        match host {
-            Host::Domain(domain_value) => domain_value.to_string(),
-            Host::Ipv4(ipv4_value) => ipv4_value.to_string(),
-            Host::Ipv6(ipv6_value) => ipv6_value.to_string(),
+    pub fn get_raw(&self) -> &str {
+        &self.raw_url
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.prober.is_ok()
+    }
+
+    pub fn probe(
+        &self,
+        engine: &Engine,
+        node: &MapServiceNode,
+        metrics: &Option<MapMetrics>,
+    ) -> ProbeOutcome {
+        match self.prober {
+            Ok(ref prober) => prober.probe(engine, node, metrics),
+            Err(reason) => ProbeOutcome::dead(reason),
         }
     }
 }
@@ -79,14 +87,47 @@ impl<'de> Deserialize<'de> for ReplicaURL {
             type Value = ReplicaURL;
 
             fn expecting(&self, format: &mut fmt::Formatter) -> fmt::Result {
-                format.write_str("an ICMP, TCP, HTTP or HTTPS url")
+                write!(
+                    format,
+                    "an url with a supported scheme ({})",
+                    prober_schemes().join(", ")
+                )
             }
 
             fn visit_str<E: Error>(self, value: &str) -> Result<ReplicaURL, E> {
-                ReplicaURL::parse_from(value).map_err(|_| E::custom("invalid"))
+                Ok(ReplicaURL::parse_lenient(value))
             }
         }
 
         de.deserialize_str(ReplicaURLVisitor)
     }
 }
+
+#[cfg(all(test, feature = "probe-tcp"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_invalid_replicas() {
+        let replicas: Vec<ReplicaURL> = serde_json::from_str(
+            r#"["tcp://127.0.0.1:22", "unknown://127.0.0.1:22", "tcp://127.0.0.1:22#addresses=bad"]"#,
+        )
+        .unwrap();
+
+        assert_eq!(replicas.len(), 3);
+        assert!(replicas[0].is_valid());
+        assert!(!replicas[1].is_valid());
+        assert!(!replicas[2].is_valid());
+
+        let outcome = replicas[1].probe(&Engine::builder().build(), &node(), &None);
+
+        assert_eq!(
+            outcome.reason.as_deref(),
+            Some("unsupported replica scheme")
+        );
+    }
+
+    fn node() -> MapServiceNode {
+        serde_json::from_str(r#"{"id": "node", "mode": "local"}"#).unwrap()
+    }
+}
@@ -21,13 +21,18 @@ use super::map::{MapService, MapServiceNode};
 use super::replica::ReplicaURL;
 use super::status::Status;
 
-use crate::APP_CONF;
-
+pub const REPORT_ENDPOINT_DEFAULT: &'static str = "https://report.crisp.watch/v1";
 pub const REPORT_HTTP_CLIENT_TIMEOUT: Duration = Duration::from_secs(20);
 
 const RETRY_STATUS_TIMES: u8 = 2;
 const RETRY_STATUS_AFTER_SECONDS: u64 = 5;
 
+pub struct ReporterClient {
+    endpoint: String,
+    authorization: String,
+    timeout: Duration,
+}
+
 #[derive(Serialize)]
 struct ReportPayload<'a> {
     replica_id: &'a str,
@@ -38,17 +43,43 @@ struct ReportPayload<'a> {
 lazy_static! {
     pub static ref REPORT_HTTP_HEADER_USERAGENT: String =
         format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
-    pub static ref REPORT_HTTP_HEADER_AUTHORIZATION: String = format!(
-        "Basic {}",
-        base64_encoder.encode(&format!(":{}", APP_CONF.report.token))
-    );
 }
 
-pub fn generate_url(path: &str) -> String {
-    format!("{}/{}", &APP_CONF.report.endpoint, path)
+impl ReporterClient {
+    pub fn new<E: Into<String>>(endpoint: E, token: &str) -> ReporterClient {
+        ReporterClient {
+            endpoint: endpoint.into(),
+            authorization: format!("Basic {}", base64_encoder.encode(format!(":{}", token))),
+            timeout: REPORT_HTTP_CLIENT_TIMEOUT,
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> ReporterClient {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn generate_url(&self, path: &str) -> String {
+        format!("{}/{}", &self.endpoint, path)
+    }
+
+    pub fn request<'a>(&'a self, request_uri: &'a Uri<'a>, method: Method) -> Request<'a> {
+        let mut request = Request::new(request_uri);
+
+        request
+            .connect_timeout(Some(self.timeout))
+            .read_timeout(Some(self.timeout))
+            .write_timeout(Some(self.timeout))
+            .method(method)
+            .header("User-Agent", &*REPORT_HTTP_HEADER_USERAGENT)
+            .header("Authorization", &self.authorization);
+
+        request
+    }
 }
 
-pub fn status(
+pub(crate) fn status(
+    reporter: &ReporterClient,
     service: &MapService,
     node: &MapServiceNode,
     replica: &ReplicaURL,
@@ -56,10 +87,11 @@ pub fn status(
     interval: u64,
 ) -> Result<(), ()> {
     // Attempt to acquire (first attempt)
-    status_attempt(service, node, replica, status, interval, 0)
+    status_attempt(reporter, service, node, replica, status, interval, 0)
 }
 
 fn status_attempt(
+    reporter: &ReporterClient,
     service: &MapService,
     node: &MapServiceNode,
     replica: &ReplicaURL,
@@ -72,7 +104,7 @@ fn status_attempt(
         attempt, service.id, node.id, replica
     );
 
-    match status_request(service, node, replica, status, interval) {
+    match status_request(reporter, service, node, replica, status, interval) {
         Ok(_) => Ok(()),
         Err(_) => {
             let next_attempt = attempt + 1;
@@ -88,13 +120,22 @@ fn status_attempt(
                 // Retry after delay
                 thread::sleep(Duration::from_secs(RETRY_STATUS_AFTER_SECONDS));
 
-                status_attempt(service, node, replica, status, interval, next_attempt)
+                status_attempt(
+                    reporter,
+                    service,
+                    node,
+                    replica,
+                    status,
+                    interval,
+                    next_attempt,
+                )
             }
         }
     }
 }
 
 fn status_request(
+    reporter: &ReporterClient,
     service: &MapService,
     node: &MapServiceNode,
     replica: &ReplicaURL,
@@ -102,7 +143,7 @@ fn status_request(
     interval: u64,
 ) -> Result<(), ()> {
     // Generate report URL
-    let report_url = generate_url(&format!("report/{}/{}", &service.id, &node.id));
+    let report_url = reporter.generate_url(&format!("report/{}/{}", &service.id, &node.id));
 
     debug!("generated report url: {}", &report_url);
 
@@ -114,22 +155,24 @@ fn status_request(
     };
 
     // Encore payload to string
-    // Notice: fail hard if payload is invalid (it should never be)
-    let payload_json = serde_json::to_vec(&payload).expect("invalid status request payload");
+    // Notice: fail this report only if payload is invalid (it should never be)
+    let payload_json = serde_json::to_vec(&payload).map_err(|err| {
+        error!("invalid status request payload (error: {})", err);
+    })?;
 
     // Generate request URI
-    let request_uri = Uri::try_from(report_url.as_str()).expect("invalid status request uri");
+    let request_uri = Uri::try_from(report_url.as_str()).map_err(|err| {
+        error!(
+            "invalid status request uri: {} (error: {})",
+            report_url, err
+        );
+    })?;
 
     // Acquire report response
     let mut response_sink = io::sink();
 
-    let response = Request::new(&request_uri)
-        .connect_timeout(Some(REPORT_HTTP_CLIENT_TIMEOUT))
-        .read_timeout(Some(REPORT_HTTP_CLIENT_TIMEOUT))
-        .write_timeout(Some(REPORT_HTTP_CLIENT_TIMEOUT))
-        .method(Method::POST)
-        .header("User-Agent", &*REPORT_HTTP_HEADER_USERAGENT)
-        .header("Authorization", &*REPORT_HTTP_HEADER_AUTHORIZATION)
+    let response = reporter
+        .request(&request_uri, Method::POST)
         .header("Content-Type", "application/json")
         .header("Content-Length", &payload_json.len())
         .body(&payload_json)
@@ -162,3 +205,172 @@ fn status_request(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+    use std::time::Instant;
+
+    use super::super::map::{acquire as map_acquire, Map, MapError};
+
+    const TOKEN_AUTHORIZATION: &'static str = "Authorization: Basic OnNlY3JldA==";
+
+    fn serve(response: &'static str, delay: Duration) -> (ReporterClient, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+
+            // Read request head and body (if any)
+            while let Ok(read) = stream.read(&mut buffer) {
+                if read == 0 {
+                    break;
+                }
+
+                request.extend_from_slice(&buffer[..read]);
+
+                let text = String::from_utf8_lossy(&request).to_string();
+
+                if let Some(head_end) = text.find("\r\n\r\n") {
+                    let length = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .and_then(|length| length.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+
+                    if request.len() >= head_end + 4 + length {
+                        break;
+                    }
+                }
+            }
+
+            thread::sleep(delay);
+
+            let _ = stream.write_all(response.as_bytes());
+
+            String::from_utf8_lossy(&request).to_string()
+        });
+
+        (ReporterClient::new(endpoint, "secret"), handle)
+    }
+
+    fn service() -> MapService {
+        serde_json::from_str(
+            r#"{"id": "service", "nodes": [{"id": "node", "replicas": ["tcp://127.0.0.1:22"]}]}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_reports_status() {
+        let (reporter, handle) = serve(
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            Duration::ZERO,
+        );
+
+        let service = service();
+        let node = &service.nodes[0];
+        let replica = &node.replicas.as_ref().unwrap()[0];
+
+        assert!(status_request(&reporter, &service, node, replica, &Status::Sick, 30).is_ok());
+
+        let request = handle.join().unwrap();
+
+        assert!(request.starts_with("POST /v1/report/service/node HTTP/1.1\r\n"));
+        assert!(request.contains(TOKEN_AUTHORIZATION));
+        assert!(request
+            .ends_with(r#"{"replica_id":"tcp://127.0.0.1:22","health":"sick","interval":30}"#));
+    }
+
+    #[test]
+    fn it_fails_reporting_status_on_error_status() {
+        let (reporter, handle) = serve(
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+            Duration::ZERO,
+        );
+
+        let service = service();
+        let node = &service.nodes[0];
+        let replica = &node.replicas.as_ref().unwrap()[0];
+
+        assert!(status_request(&reporter, &service, node, replica, &Status::Dead, 30).is_err());
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn it_times_out_reporting_status() {
+        let (reporter, handle) = serve(
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+            Duration::from_secs(2),
+        );
+
+        let reporter = reporter.timeout(Duration::from_millis(200));
+        let service = service();
+        let node = &service.nodes[0];
+        let replica = &node.replicas.as_ref().unwrap()[0];
+
+        let start_time = Instant::now();
+
+        assert!(status_request(&reporter, &service, node, replica, &Status::Healthy, 30).is_err());
+        assert!(start_time.elapsed() < Duration::from_secs(2));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn it_acquires_map() {
+        let (reporter, handle) = serve(
+            concat!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 38\r\n\r\n",
+                r#"{"data": {"date": 20, "services": []}}"#
+            ),
+            Duration::ZERO,
+        );
+
+        let mut map = Map {
+            date: Some(10),
+            metrics: None,
+            services: Vec::new(),
+        };
+
+        assert!(map_acquire(&reporter, &mut map).is_ok());
+        assert_eq!(map.date, Some(20));
+
+        let request = handle.join().unwrap();
+
+        assert!(request.starts_with("GET /v1/probes/local?since=10 HTTP/1.1\r\n"));
+        assert!(request.contains(TOKEN_AUTHORIZATION));
+    }
+
+    #[test]
+    fn it_refuses_map_when_not_authorized() {
+        let (reporter, handle) = serve(
+            "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n",
+            Duration::ZERO,
+        );
+
+        let mut map = Map {
+            date: None,
+            metrics: None,
+            services: Vec::new(),
+        };
+
+        assert!(matches!(
+            map_acquire(&reporter, &mut map),
+            Err(MapError::NotAuthorized)
+        ));
+
+        assert!(handle
+            .join()
+            .unwrap()
+            .starts_with("GET /v1/probes/local HTTP/1.1\r\n"));
+    }
+}
@@ -0,0 +1,682 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::bind::{connect as bind_connect, Bind};
+use super::metrics::Metrics;
+
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_CLASS_IN: u16 = 1;
+const DNS_FLAG_TRUNCATED: u16 = 0x0200;
+const DNS_RCODE_NXDOMAIN: u16 = 3;
+const DNS_BUFFER_SIZE: usize = 1232;
+
+pub struct Resolver {
+    settings: ResolverSettings,
+    cache: Mutex<HashMap<String, ResolverEntry>>,
+}
+
+#[derive(Clone)]
+pub struct ResolverSettings {
+    pub upstreams: Vec<SocketAddr>,
+    pub timeout: Duration,
+    pub system_ttl: Duration,
+    pub maximum_ttl: Duration,
+    pub stale_ttl: Duration,
+    pub bind: Option<Bind>,
+}
+
+struct ResolverEntry {
+    addresses: Vec<IpAddr>,
+    expires: Instant,
+    stale_expires: Instant,
+}
+
+struct ResolverQuery {
+    id: u16,
+    record_type: u16,
+}
+
+impl Default for ResolverSettings {
+    fn default() -> ResolverSettings {
+        // Notice: the system resolver is used by default, as it does not return any TTL, \
+        //   its answers are only cached for a short while (ie. re-used for retries).
+        ResolverSettings {
+            upstreams: Vec::new(),
+            timeout: Duration::from_secs(2),
+            system_ttl: Duration::from_secs(10),
+            maximum_ttl: Duration::from_secs(300),
+            stale_ttl: Duration::from_secs(300),
+            bind: None,
+        }
+    }
+}
+
+impl Resolver {
+    pub fn new(settings: ResolverSettings) -> Resolver {
+        Resolver {
+            settings: settings,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn resolve(&self, metrics: &Metrics, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        // IP hosts do not need to be resolved
+        if let Ok(address) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(address, port)]);
+        }
+
+        let host = host.trim_end_matches('.').to_lowercase();
+        let now = Instant::now();
+
+        // Serve from cache? (if not expired)
+        if let Some(entry) = self.cache().get(&host) {
+            if entry.expires > now {
+                debug!("resolved host from cache: {}", host);
+
+                metrics.count_resolve_cache_hit();
+
+                return Ok(to_socket_addresses(&entry.addresses, port));
+            }
+        }
+
+        match self.lookup(&host) {
+            Ok((addresses, ttl)) => {
+                debug!(
+                    "resolved host: {} to: {:?} (ttl: {}s)",
+                    host,
+                    addresses,
+                    ttl.as_secs()
+                );
+
+                let socket_addresses = to_socket_addresses(&addresses, port);
+                let mut cache = self.cache();
+
+                // Forget about entries that cannot be served anymore (even as stale)
+                cache.retain(|_, entry| entry.stale_expires > now);
+
+                cache.insert(
+                    host,
+                    ResolverEntry {
+                        addresses: addresses,
+                        expires: now + ttl,
+                        stale_expires: now + ttl + self.settings.stale_ttl,
+                    },
+                );
+
+                Ok(socket_addresses)
+            }
+            Err(err) => {
+                // Serve stale addresses? (only if the resolver failed, and not if the host \
+                //   does not exist anymore)
+                if err.kind() != ErrorKind::NotFound {
+                    if let Some(entry) = self.cache().get(&host) {
+                        if entry.stale_expires > now {
+                            warn!(
+                                "resolver failed for host: {}, serving stale addresses (error: {})",
+                                host, err
+                            );
+
+                            metrics.count_resolve_stale();
+
+                            return Ok(to_socket_addresses(&entry.addresses, port));
+                        }
+                    }
+                }
+
+                warn!("could not resolve host: {} (error: {})", host, err);
+
+                Err(err)
+            }
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, HashMap<String, ResolverEntry>> {
+        // Notice: the cache stays consistent even if a panicking probe poisoned its lock
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lookup(&self, host: &str) -> io::Result<(Vec<IpAddr>, Duration)> {
+        // Resolve using the system resolver? (if no upstream is configured)
+        if self.settings.upstreams.is_empty() == true {
+            let addresses: Vec<IpAddr> = (host, 0)
+                .to_socket_addrs()?
+                .map(|address| address.ip())
+                .collect();
+
+            return if addresses.is_empty() == true {
+                Err(io::Error::new(ErrorKind::NotFound, "no address found"))
+            } else {
+                Ok((addresses, self.settings.system_ttl))
+            };
+        }
+
+        // Query upstreams in order, until one of them answers
+        let mut last_error = None;
+
+        for upstream in &self.settings.upstreams {
+            match self.lookup_upstream(upstream, host) {
+                Ok((addresses, ttl)) => {
+                    return if addresses.is_empty() == true {
+                        Err(io::Error::new(ErrorKind::NotFound, "no address found"))
+                    } else {
+                        Ok((addresses, ttl.min(self.settings.maximum_ttl)))
+                    };
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => return Err(err),
+                Err(err) => {
+                    debug!(
+                        "resolver upstream: {} failed for host: {} (error: {})",
+                        upstream, host, err
+                    );
+
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| io::Error::other("no upstream")))
+    }
+
+    fn lookup_upstream(
+        &self,
+        upstream: &SocketAddr,
+        host: &str,
+    ) -> io::Result<(Vec<IpAddr>, Duration)> {
+        let socket = self.bind_upstream(upstream)?;
+
+        socket.connect(upstream)?;
+
+        // Query for both IPv4 and IPv6 addresses at once
+        let mut queries = Vec::new();
+
+        for record_type in &[DNS_TYPE_A, DNS_TYPE_AAAA] {
+            let query = ResolverQuery {
+                id: RandomState::new().build_hasher().finish() as u16,
+                record_type: *record_type,
+            };
+
+            socket.send(&query.encode(host)?)?;
+
+            queries.push(query);
+        }
+
+        let start_time = Instant::now();
+        let (mut addresses, mut ttl) = (Vec::new(), None);
+        let mut last_error = None;
+        let mut buffer = [0; DNS_BUFFER_SIZE];
+
+        while queries.is_empty() == false {
+            let remaining = match self.remaining(start_time) {
+                Ok(remaining) => remaining,
+                Err(err) => {
+                    last_error = Some(err);
+
+                    break;
+                }
+            };
+
+            socket.set_read_timeout(Some(remaining))?;
+
+            let size = match socket.recv(&mut buffer) {
+                Ok(size) => size,
+                Err(ref err)
+                    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                {
+                    last_error = Some(io::Error::new(ErrorKind::TimedOut, "upstream timed out"));
+
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+
+            let response = &buffer[..size];
+
+            // Ignore responses to unknown queries (eg. late responses)
+            let index = match queries
+                .iter()
+                .position(|query| response.len() >= 2 && response[0..2] == query.id.to_be_bytes())
+            {
+                Some(index) => index,
+                None => continue,
+            };
+
+            let query = queries.remove(index);
+
+            // Notice: truncated responses may be missing addresses, so the query is retried \
+            //   over TCP (as upstreams are required to support it)
+            let answer = if is_truncated(response) == true {
+                debug!(
+                    "resolver upstream: {} truncated response for host: {}, retrying over tcp",
+                    upstream, host
+                );
+
+                self.remaining(start_time).and_then(|remaining| {
+                    self.lookup_upstream_tcp(upstream, host, &query, remaining)
+                })
+            } else {
+                query.decode(response)
+            };
+
+            match answer {
+                Ok((answer_addresses, answer_ttl)) => {
+                    addresses.extend(answer_addresses);
+
+                    ttl = match (ttl, answer_ttl) {
+                        (Some(ttl), Some(answer_ttl)) => Some(answer_ttl.min(ttl)),
+                        (ttl, answer_ttl) => ttl.or(answer_ttl),
+                    };
+                }
+                // Notice: a host that does not exist does not exist for any record type
+                Err(err) if err.kind() == ErrorKind::NotFound => return Err(err),
+                Err(err) => {
+                    debug!(
+                        "resolver upstream: {} failed to answer a query for host: {} (error: {})",
+                        upstream, host, err
+                    );
+
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        // Notice: a failed query (eg. an AAAA query that timed out) does not fail the whole \
+        //   lookup if the other query returned addresses, as those addresses are still valid
+        if let Some(err) = last_error {
+            if addresses.is_empty() == true {
+                return Err(err);
+            }
+
+            warn!(
+                "resolver upstream: {} returned a partial answer for host: {} (error: {})",
+                upstream, host, err
+            );
+        }
+
+        // Notice: answers may be received in any order, so IPv4 addresses are always returned \
+        //   first (as the order matters to the default address policy)
+        addresses.sort_by_key(|address| address.is_ipv6());
+
+        Ok((addresses, ttl.unwrap_or(Duration::ZERO)))
+    }
+
+    fn lookup_upstream_tcp(
+        &self,
+        upstream: &SocketAddr,
+        host: &str,
+        query: &ResolverQuery,
+        timeout: Duration,
+    ) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+        let mut stream = bind_connect(self.settings.bind.as_ref(), upstream, timeout)?;
+
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        // Messages sent over TCP are prefixed with their size
+        let packet = query.encode(host)?;
+        let mut message = Vec::with_capacity(packet.len() + 2);
+
+        message.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+        message.extend_from_slice(&packet);
+
+        stream.write_all(&message)?;
+
+        let mut size = [0; 2];
+
+        stream.read_exact(&mut size)?;
+
+        let mut response = vec![0; u16::from_be_bytes(size) as usize];
+
+        stream.read_exact(&mut response)?;
+
+        if response.get(0..2) != Some(&query.id.to_be_bytes()[..]) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "mismatching upstream response",
+            ));
+        }
+
+        query.decode(&response)
+    }
+
+    fn bind_upstream(&self, upstream: &SocketAddr) -> io::Result<UdpSocket> {
+        // Notice: upstream queries originate from the engine bind (if any), as the upstreams \
+        //   may only be reachable from there
+        match self.settings.bind {
+            Some(ref bind) => {
+                let socket = Socket::new(
+                    Domain::for_address(*upstream),
+                    Type::DGRAM,
+                    Some(Protocol::UDP),
+                )?;
+
+                bind.apply(&socket, upstream)?;
+
+                Ok(socket.into())
+            }
+            None => {
+                let bind_address: SocketAddr = if upstream.is_ipv4() == true {
+                    (Ipv4Addr::UNSPECIFIED, 0).into()
+                } else {
+                    (Ipv6Addr::UNSPECIFIED, 0).into()
+                };
+
+                UdpSocket::bind(bind_address)
+            }
+        }
+    }
+
+    fn remaining(&self, start_time: Instant) -> io::Result<Duration> {
+        match self.settings.timeout.checked_sub(start_time.elapsed()) {
+            Some(remaining) if remaining > Duration::ZERO => Ok(remaining),
+            _ => Err(io::Error::new(ErrorKind::TimedOut, "upstream timed out")),
+        }
+    }
+}
+
+impl ResolverQuery {
+    fn encode(&self, host: &str) -> io::Result<Vec<u8>> {
+        // Header (recursion desired, with a single question)
+        let mut packet = Vec::with_capacity(host.len() + 18);
+
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        // Question
+        for label in host.split('.') {
+            if label.is_empty() == true || label.len() > 63 {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "invalid host"));
+            }
+
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+
+        packet.push(0);
+        packet.extend_from_slice(&self.record_type.to_be_bytes());
+        packet.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+
+        Ok(packet)
+    }
+
+    fn decode(&self, response: &[u8]) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid upstream response");
+
+        if response.len() < 12 {
+            return Err(invalid());
+        }
+
+        let flags = read_u16(response, 2).ok_or_else(invalid)?;
+
+        match flags & 0x000f {
+            0 => {}
+            DNS_RCODE_NXDOMAIN => {
+                return Err(io::Error::new(ErrorKind::NotFound, "host does not exist"));
+            }
+            rcode => {
+                return Err(io::Error::other(format!(
+                    "upstream answered with error code: {}",
+                    rcode
+                )));
+            }
+        }
+
+        let question_count = read_u16(response, 4).ok_or_else(invalid)?;
+        let answer_count = read_u16(response, 6).ok_or_else(invalid)?;
+
+        let mut offset = 12;
+
+        for _ in 0..question_count {
+            offset = skip_name(response, offset).ok_or_else(invalid)? + 4;
+        }
+
+        // Collect addresses from answers (CNAME records are skipped, as resolvers also answer \
+        //   with the records of the canonical name)
+        let (mut addresses, mut ttl) = (Vec::new(), None);
+
+        for _ in 0..answer_count {
+            offset = skip_name(response, offset).ok_or_else(invalid)?;
+
+            let record_type = read_u16(response, offset).ok_or_else(invalid)?;
+            let record_ttl = read_u32(response, offset + 4).ok_or_else(invalid)?;
+            let data_size = read_u16(response, offset + 8).ok_or_else(invalid)? as usize;
+            let data = response
+                .get((offset + 10)..(offset + 10 + data_size))
+                .ok_or_else(invalid)?;
+
+            let address = match (record_type, data_size) {
+                (DNS_TYPE_A, 4) => {
+                    let mut octets = [0; 4];
+
+                    octets.copy_from_slice(data);
+
+                    Some(IpAddr::from(octets))
+                }
+                (DNS_TYPE_AAAA, 16) => {
+                    let mut octets = [0; 16];
+
+                    octets.copy_from_slice(data);
+
+                    Some(IpAddr::from(octets))
+                }
+                _ => None,
+            };
+
+            if let Some(address) = address.filter(|_| record_type == self.record_type) {
+                addresses.push(address);
+
+                ttl = Some(ttl.map_or(record_ttl, |ttl: u32| ttl.min(record_ttl)));
+            }
+
+            offset += 10 + data_size;
+        }
+
+        Ok((addresses, ttl.map(|ttl| Duration::from_secs(ttl as u64))))
+    }
+}
+
+fn is_truncated(response: &[u8]) -> bool {
+    read_u16(response, 2).is_some_and(|flags| flags & DNS_FLAG_TRUNCATED != 0)
+}
+
+fn skip_name(packet: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let length = *packet.get(offset)?;
+
+        match length {
+            0 => return Some(offset + 1),
+            // Notice: compressed names end with a pointer to another name
+            length if length & 0xc0 == 0xc0 => return Some(offset + 2),
+            length => offset += 1 + length as usize,
+        }
+    }
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    packet
+        .get(offset..(offset + 2))
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(packet: &[u8], offset: usize) -> Option<u32> {
+    packet
+        .get(offset..(offset + 4))
+        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn to_socket_addresses(addresses: &[IpAddr], port: u16) -> Vec<SocketAddr> {
+    addresses
+        .iter()
+        .map(|address| SocketAddr::new(*address, port))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn answer(query: &[u8], flags: u16, addresses: &[IpAddr]) -> Vec<u8> {
+        let mut response = Vec::new();
+
+        response.extend_from_slice(&query[0..2]);
+        response.extend_from_slice(&flags.to_be_bytes());
+        response.extend_from_slice(&[0x00, 0x01]);
+        response.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        response.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        response.extend_from_slice(&query[12..]);
+
+        for address in addresses {
+            let (record_type, data) = match address {
+                IpAddr::V4(address) => (DNS_TYPE_A, address.octets().to_vec()),
+                IpAddr::V6(address) => (DNS_TYPE_AAAA, address.octets().to_vec()),
+            };
+
+            response.extend_from_slice(&[0xc0, 0x0c]);
+            response.extend_from_slice(&record_type.to_be_bytes());
+            response.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&60_u32.to_be_bytes());
+            response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            response.extend_from_slice(&data);
+        }
+
+        response
+    }
+
+    fn serve<F>(respond: F) -> Resolver
+    where
+        F: Fn(u16, &[u8]) -> Option<Vec<u8>> + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream = socket.local_addr().unwrap();
+
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+
+        thread::spawn(move || {
+            let mut buffer = [0; DNS_BUFFER_SIZE];
+
+            while let Ok((size, peer)) = socket.recv_from(&mut buffer) {
+                let query = &buffer[..size];
+
+                if let Some(response) = respond(read_u16(query, size - 4).unwrap(), query) {
+                    socket.send_to(&response, peer).unwrap();
+                }
+            }
+        });
+
+        Resolver::new(ResolverSettings {
+            upstreams: vec![upstream],
+            timeout: Duration::from_millis(300),
+            ..ResolverSettings::default()
+        })
+    }
+
+    #[test]
+    fn it_keeps_partial_answers() {
+        // Answer A queries, though never answer AAAA queries
+        let resolver = serve(|record_type, query| match record_type {
+            DNS_TYPE_A => Some(answer(query, 0x8180, &["192.0.2.1".parse().unwrap()])),
+            _ => None,
+        });
+
+        assert_eq!(
+            resolver
+                .resolve(&Metrics::default(), "partial.test", 80)
+                .unwrap(),
+            vec!["192.0.2.1:80".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn it_fails_when_no_query_is_answered() {
+        let resolver = serve(|_, _| None);
+
+        assert_eq!(
+            resolver
+                .resolve(&Metrics::default(), "silent.test", 80)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn it_retries_truncated_answers_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut size = [0; 2];
+
+            stream.read_exact(&mut size).unwrap();
+
+            let mut query = vec![0; u16::from_be_bytes(size) as usize];
+
+            stream.read_exact(&mut query).unwrap();
+
+            let response = answer(
+                &query,
+                0x8180,
+                &["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()],
+            );
+
+            stream
+                .write_all(&(response.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&response).unwrap();
+        });
+
+        // Notice: the UDP upstream must listen on the same port as the TCP upstream
+        let socket = UdpSocket::bind(("127.0.0.1", port)).unwrap();
+
+        thread::spawn(move || {
+            let mut buffer = [0; DNS_BUFFER_SIZE];
+
+            while let Ok((size, peer)) = socket.recv_from(&mut buffer) {
+                let query = &buffer[..size];
+
+                // Truncate A answers, and answer AAAA queries with no address
+                let response = match read_u16(query, size - 4).unwrap() {
+                    DNS_TYPE_A => answer(query, 0x8380, &[]),
+                    _ => answer(query, 0x8180, &[]),
+                };
+
+                socket.send_to(&response, peer).unwrap();
+            }
+        });
+
+        let resolver = Resolver::new(ResolverSettings {
+            upstreams: vec![SocketAddr::from(([127, 0, 0, 1], port))],
+            timeout: Duration::from_millis(500),
+            ..ResolverSettings::default()
+        });
+
+        assert_eq!(
+            resolver
+                .resolve(&Metrics::default(), "truncated.test", 80)
+                .unwrap(),
+            vec![
+                "192.0.2.1:80".parse::<SocketAddr>().unwrap(),
+                "192.0.2.2:80".parse::<SocketAddr>().unwrap()
+            ]
+        );
+    }
+}
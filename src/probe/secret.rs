@@ -0,0 +1,134 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::fs;
+
+use super::engine::Engine;
+use super::tls::matches_host_pattern;
+
+const SECRET_PLACEHOLDER_START: &'static str = "{{secret:";
+const SECRET_PLACEHOLDER_END: &'static str = "}}";
+
+pub fn substitute(engine: &Engine, value: &str, host: &str) -> Result<String, &'static str> {
+    // Replace all secret placeholders (eg. '{{secret:api_token}}') with the contents of the \
+    //   matching local secret file. As the probe map is pulled from a remote server, secrets can \
+    //   only be read from files that are explicitly declared in the local configuration, and \
+    //   only be sent to the hosts that they are declared for.
+    let mut substituted = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find(SECRET_PLACEHOLDER_START) {
+        let after_start = &rest[(start + SECRET_PLACEHOLDER_START.len())..];
+        let end = after_start
+            .find(SECRET_PLACEHOLDER_END)
+            .ok_or("secret placeholder is not closed")?;
+
+        substituted.push_str(&rest[..start]);
+        substituted.push_str(&read(engine, after_start[..end].trim(), host)?);
+
+        rest = &after_start[(end + SECRET_PLACEHOLDER_END.len())..];
+    }
+
+    substituted.push_str(rest);
+
+    Ok(substituted)
+}
+
+pub fn contains(value: &str) -> bool {
+    value.contains(SECRET_PLACEHOLDER_START)
+}
+
+fn read(engine: &Engine, name: &str, host: &str) -> Result<String, &'static str> {
+    let secret = engine.secrets.get(name).ok_or_else(|| {
+        error!("secret is not declared in local configuration: {}", name);
+
+        "secret is not declared"
+    })?;
+
+    // Notice: a remotely-configured node could otherwise get any secret sent to a host of its \
+    //   choosing, hence the host check.
+    if secret
+        .hosts
+        .iter()
+        .any(|pattern| matches_host_pattern(pattern, host))
+        == false
+    {
+        error!(
+            "refused sending secret: {} to host: {}, as it is not in its allowed hosts",
+            name, host
+        );
+
+        return Err("secret is not allowed for host");
+    }
+
+    // Notice: secret files are read upon every use, so that rotated secrets get picked up \
+    //   without restarting the daemon (trailing line breaks are stripped).
+    match fs::read_to_string(&secret.path) {
+        Ok(value) => Ok(value.trim_end_matches(&['\r', '\n'][..]).to_owned()),
+        Err(err) => {
+            error!(
+                "could not read secret: {} from file: {:?} (error: {})",
+                name, secret.path, err
+            );
+
+            Err("secret could not be read")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    fn engine(test: &str) -> Engine {
+        // Notice: each test uses its own secret file, as tests run in parallel
+        let path = env::temp_dir().join(format!(
+            "crisp-status-local-secret-{}-{}",
+            std::process::id(),
+            test
+        ));
+
+        fs::write(&path, "s3cr3t\n").unwrap();
+
+        Engine::builder()
+            .secret("token", path, vec!["*.internal".to_string()])
+            .build()
+    }
+
+    #[test]
+    fn substitutes_secret_for_allowed_host() {
+        assert_eq!(
+            substitute(
+                &engine("allowed"),
+                "Bearer {{secret:token}}",
+                "api.internal"
+            ),
+            Ok("Bearer s3cr3t".to_string())
+        );
+    }
+
+    #[test]
+    fn refuses_secret_for_other_host() {
+        assert_eq!(
+            substitute(
+                &engine("other"),
+                "Bearer {{secret:token}}",
+                "attacker.example.com"
+            ),
+            Err("secret is not allowed for host")
+        );
+    }
+
+    #[test]
+    fn refuses_undeclared_secret() {
+        assert_eq!(
+            substitute(&engine("undeclared"), "{{secret:other}}", "api.internal"),
+            Err("secret is not declared")
+        );
+    }
+}
@@ -0,0 +1,727 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use base64::engine::general_purpose::STANDARD_NO_PAD as base64_encoder;
+use base64::Engine as Base64Engine;
+use ring::agreement::{self, agree_ephemeral, EphemeralPrivateKey, ECDH_P256, X25519};
+use ring::digest::{digest, Context, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{
+    self, RsaPublicKeyComponents, ECDSA_P256_SHA256_FIXED, ED25519, RSA_PKCS1_2048_8192_SHA256,
+    RSA_PKCS1_2048_8192_SHA512,
+};
+use url::Url;
+
+use std::io::{Read, Write};
+
+use super::address::{AddressFamily, AddressPolicy};
+use super::bind::{connect as bind_connect, Bind};
+use super::engine::Engine;
+use super::map::{MapMetrics, MapServiceNode};
+use super::prober::{
+    acquire_dead_timeout, host_string, option as prober_option, options as prober_options,
+    ProbeOutcome, Prober,
+};
+
+const SSH_IDENTIFICATION_MAXIMUM_LINES: usize = 32;
+const SSH_IDENTIFICATION_MAXIMUM_SIZE: usize = 255;
+const SSH_PACKET_MAXIMUM_SIZE: usize = 65536;
+const SSH_PACKETS_MAXIMUM: usize = 32;
+
+const SSH_KEX_ALGORITHMS: &'static str =
+    "curve25519-sha256,curve25519-sha256@libssh.org,ecdh-sha2-nistp256";
+const SSH_HOST_KEY_ALGORITHMS: &'static str =
+    "ssh-ed25519,ecdsa-sha2-nistp256,rsa-sha2-512,rsa-sha2-256";
+const SSH_CIPHER_ALGORITHMS: &'static str =
+    "chacha20-poly1305@openssh.com,aes128-ctr,aes256-ctr,aes128-gcm@openssh.com,\
+        aes256-gcm@openssh.com";
+const SSH_MAC_ALGORITHMS: &'static str =
+    "hmac-sha2-256-etm@openssh.com,hmac-sha2-512-etm@openssh.com,hmac-sha2-256,hmac-sha2-512";
+const SSH_COMPRESSION_ALGORITHMS: &'static str = "none";
+
+const MESSAGE_DISCONNECT: u8 = 1;
+const MESSAGE_IGNORE: u8 = 2;
+const MESSAGE_DEBUG: u8 = 4;
+const MESSAGE_KEXINIT: u8 = 20;
+const MESSAGE_KEX_ECDH_INIT: u8 = 30;
+const MESSAGE_KEX_ECDH_REPLY: u8 = 31;
+
+lazy_static! {
+    static ref SSH_IDENTIFICATION: String = format!(
+        "SSH-2.0-{}_{}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+}
+
+#[derive(Debug)]
+pub struct SSHProber {
+    host: String,
+    port: u16,
+    fingerprint: Option<String>,
+    mismatch_dead: bool,
+    addresses: AddressPolicy,
+    family: Option<AddressFamily>,
+    bind: Option<Bind>,
+}
+
+#[derive(Debug)]
+pub enum SSHHostKeyStatus {
+    Unverified,
+    Unsupported,
+    Matching,
+    Mismatching,
+}
+
+struct SSHReader<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> SSHReader<'a> {
+    fn new(buffer: &'a [u8]) -> SSHReader<'a> {
+        SSHReader { buffer: buffer }
+    }
+
+    fn read_bytes(&mut self, size: usize) -> Result<&'a [u8], ()> {
+        if size > self.buffer.len() {
+            return Err(());
+        }
+
+        let (bytes, buffer) = self.buffer.split_at(size);
+
+        self.buffer = buffer;
+
+        Ok(bytes)
+    }
+
+    fn read_string(&mut self) -> Result<&'a [u8], ()> {
+        let size = self.read_bytes(4)?;
+
+        self.read_bytes(u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize)
+    }
+
+    fn read_mpint(&mut self) -> Result<&'a [u8], ()> {
+        // Strip leading zeroes (used to mark positive integers with their high bit set)
+        let value = self.read_string()?;
+
+        Ok(&value[value.iter().take_while(|byte| **byte == 0).count()..])
+    }
+}
+
+impl Prober for SSHProber {
+    fn parse(url: &Url) -> Result<SSHProber, ()> {
+        // Notice: the expected host key fingerprint is passed using the 'fingerprint' replica \
+        //   option (eg. 'ssh://bastion.local:22#fingerprint=SHA256:...'), and a mismatching \
+        //   host key reports the replica as sick, unless 'mismatch=dead' is passed. As base64 \
+        //   fingerprints may contain '+' characters, which get decoded as spaces in options, \
+        //   those are restored here.
+        let options = prober_options(url);
+
+        let fingerprint = prober_option::<String>(&options, "fingerprint")?
+            .map(|fingerprint| fingerprint.replace(' ', "+"));
+
+        let mismatch_dead = match prober_option::<String>(&options, "mismatch")?.as_deref() {
+            None | Some("sick") => false,
+            Some("dead") => true,
+            Some(_) => return Err(()),
+        };
+
+        match url.host() {
+            Some(host) => Ok(SSHProber {
+                host: host_string(host),
+                port: url.port().unwrap_or(22),
+                fingerprint: fingerprint,
+                mismatch_dead: mismatch_dead,
+                addresses: AddressPolicy::from_options(&options)?,
+                family: AddressFamily::from_options(&options)?,
+                bind: Bind::from_options(&options)?,
+            }),
+            _ => Err(()),
+        }
+    }
+
+    fn probe(
+        &self,
+        engine: &Engine,
+        _node: &MapServiceNode,
+        metrics: &Option<MapMetrics>,
+    ) -> ProbeOutcome {
+        let dead_timeout = acquire_dead_timeout(metrics);
+        let bind = engine.bind_for(&self.bind);
+
+        engine.probe_addresses(
+            &self.host,
+            self.port,
+            self.family,
+            self.addresses,
+            bind,
+            |address| {
+                debug!("prober poll will fire for ssh target: {}", address);
+
+                let stream = match bind_connect(bind, &address, dead_timeout) {
+                    Ok(stream) => stream,
+                    Err(err) => return ProbeOutcome::dead(format!("connection failed: {}", err)),
+                };
+
+                // Notice: as SSH servers are expected to send their identification right after \
+                //   the connection is opened, a server that hangs before sending it is \
+                //   considered dead.
+                if stream.set_read_timeout(Some(dead_timeout)).is_err()
+                    || stream.set_write_timeout(Some(dead_timeout)).is_err()
+                {
+                    return ProbeOutcome::dead("could not set socket timeouts");
+                }
+
+                match check(stream, &self.fingerprint) {
+                    Ok(SSHHostKeyStatus::Unverified) | Ok(SSHHostKeyStatus::Matching) => {
+                        ProbeOutcome::healthy()
+                    }
+                    Ok(SSHHostKeyStatus::Unsupported) => {
+                        // Notice: the server is alive (as it sent its identification), though \
+                        //   its host key could not be verified.
+                        ProbeOutcome::sick("host key unverified: no supported key exchange")
+                    }
+                    Ok(SSHHostKeyStatus::Mismatching) => {
+                        warn!(
+                            "prober poll got mismatching host key for ssh target: {}",
+                            address
+                        );
+
+                        if self.mismatch_dead == true {
+                            ProbeOutcome::dead("host key mismatch")
+                        } else {
+                            ProbeOutcome::sick("host key mismatch")
+                        }
+                    }
+                    Err(_) => {
+                        debug!(
+                            "prober poll conversation failed for ssh target: {}",
+                            address
+                        );
+
+                        ProbeOutcome::dead("conversation failed")
+                    }
+                }
+            },
+        )
+    }
+}
+
+fn check<S: Read + Write>(
+    mut stream: S,
+    fingerprint: &Option<String>,
+) -> Result<SSHHostKeyStatus, ()> {
+    // Exchange identifications (the server one must be received for the server to be alive)
+    stream
+        .write_all(format!("{}\r\n", &*SSH_IDENTIFICATION).as_bytes())
+        .and_then(|_| stream.flush())
+        .or(Err(()))?;
+
+    let server_identification = read_identification(&mut stream)?;
+
+    debug!("got ssh server identification: {}", server_identification);
+
+    match fingerprint {
+        Some(fingerprint_inner) => {
+            let host_key = match exchange_keys(&mut stream, &server_identification)? {
+                Some(host_key) => host_key,
+                None => return Ok(SSHHostKeyStatus::Unsupported),
+            };
+
+            // Say goodbye (the key exchange is not completed, as we do not need to go further)
+            write_disconnect(&mut stream).ok();
+
+            let host_key_fingerprint = format!(
+                "SHA256:{}",
+                base64_encoder.encode(digest(&SHA256, &host_key))
+            );
+
+            debug!(
+                "got ssh server host key with fingerprint: {} (expected: {})",
+                host_key_fingerprint, fingerprint_inner
+            );
+
+            if &host_key_fingerprint == fingerprint_inner {
+                Ok(SSHHostKeyStatus::Matching)
+            } else {
+                Ok(SSHHostKeyStatus::Mismatching)
+            }
+        }
+        None => Ok(SSHHostKeyStatus::Unverified),
+    }
+}
+
+fn read_identification<S: Read + Write>(stream: &mut S) -> Result<String, ()> {
+    // Notice: servers are allowed to send other lines of data before their identification \
+    //   string, which must be skipped (eg. pre-authentication banners).
+    for _ in 0..SSH_IDENTIFICATION_MAXIMUM_LINES {
+        let mut line = Vec::new();
+        let mut byte = [0; 1];
+
+        while line.ends_with(b"\n") == false {
+            if line.len() > SSH_IDENTIFICATION_MAXIMUM_SIZE {
+                return Err(());
+            }
+
+            stream.read_exact(&mut byte).or(Err(()))?;
+
+            line.push(byte[0]);
+        }
+
+        let line = String::from_utf8_lossy(&line)
+            .trim_end_matches(&['\r', '\n'][..])
+            .to_string();
+
+        if line.starts_with("SSH-2.0-") || line.starts_with("SSH-1.99-") {
+            return Ok(line);
+        }
+
+        if line.starts_with("SSH-") {
+            debug!("got unsupported ssh server identification: {}", line);
+
+            return Err(());
+        }
+    }
+
+    Err(())
+}
+
+fn exchange_keys<S: Read + Write>(
+    stream: &mut S,
+    server_identification: &str,
+) -> Result<Option<Vec<u8>>, ()> {
+    let random = SystemRandom::new();
+
+    // Exchange key exchange initialization messages
+    let client_kexinit = make_kexinit(&random)?;
+
+    write_packet(stream, &client_kexinit)?;
+
+    let server_kexinit = read_packet_of(stream, MESSAGE_KEXINIT)?;
+
+    let kex_algorithm = match negotiate_kex(&server_kexinit)? {
+        Some(kex_algorithm) => kex_algorithm,
+        None => {
+            write_disconnect(stream).ok();
+
+            return Ok(None);
+        }
+    };
+
+    // Generate ephemeral key, and send it to the server
+    let private_key = EphemeralPrivateKey::generate(kex_algorithm, &random).or(Err(()))?;
+    let client_public_key = private_key.compute_public_key().or(Err(()))?;
+
+    let mut ecdh_init = vec![MESSAGE_KEX_ECDH_INIT];
+
+    write_string(&mut ecdh_init, client_public_key.as_ref());
+    write_packet(stream, &ecdh_init)?;
+
+    // Acquire server host key and ephemeral key, alongside with exchange hash signature
+    let ecdh_reply = read_packet_of(stream, MESSAGE_KEX_ECDH_REPLY)?;
+
+    let mut reader = SSHReader::new(&ecdh_reply[1..]);
+
+    let host_key = reader.read_string()?;
+    let server_public_key = reader.read_string()?;
+    let signature = reader.read_string()?;
+
+    // Compute shared secret and exchange hash
+    let shared_secret = agree_ephemeral(
+        private_key,
+        &agreement::UnparsedPublicKey::new(kex_algorithm, server_public_key),
+        |secret| secret.to_vec(),
+    )
+    .or(Err(()))?;
+
+    let mut exchange_hash = Context::new(&SHA256);
+    let mut exchange_data = Vec::new();
+
+    write_string(&mut exchange_data, SSH_IDENTIFICATION.as_bytes());
+    write_string(&mut exchange_data, server_identification.as_bytes());
+    write_string(&mut exchange_data, &client_kexinit);
+    write_string(&mut exchange_data, &server_kexinit);
+    write_string(&mut exchange_data, host_key);
+    write_string(&mut exchange_data, client_public_key.as_ref());
+    write_string(&mut exchange_data, server_public_key);
+    write_mpint(&mut exchange_data, &shared_secret);
+
+    exchange_hash.update(&exchange_data);
+
+    // Verify exchange hash signature (proves that the server holds the host private key)
+    verify_signature(host_key, signature, exchange_hash.finish().as_ref())?;
+
+    Ok(Some(host_key.to_vec()))
+}
+
+fn negotiate_kex(server_kexinit: &[u8]) -> Result<Option<&'static agreement::Algorithm>, ()> {
+    // Skip message type and cookie, then read the server key exchange algorithms
+    let mut reader = SSHReader::new(server_kexinit.get(17..).ok_or(())?);
+
+    let server_algorithms: Vec<&[u8]> = reader.read_string()?.split(|byte| *byte == b',').collect();
+
+    // Notice: the negotiated algorithm is the first client algorithm that is also supported by \
+    //   the server (as per RFC 4253); both use SHA-256 as their exchange hash.
+    let kex_algorithm = SSH_KEX_ALGORITHMS
+        .split(',')
+        .find(|algorithm| server_algorithms.contains(&algorithm.as_bytes()));
+
+    debug!("negotiated ssh key exchange algorithm: {:?}", kex_algorithm);
+
+    Ok(match kex_algorithm {
+        Some("ecdh-sha2-nistp256") => Some(&ECDH_P256),
+        Some(_) => Some(&X25519),
+        None => None,
+    })
+}
+
+fn verify_signature(host_key: &[u8], signature: &[u8], message: &[u8]) -> Result<(), ()> {
+    let mut host_key_reader = SSHReader::new(host_key);
+    let mut signature_reader = SSHReader::new(signature);
+
+    let host_key_algorithm = host_key_reader.read_string()?;
+    let signature_algorithm = signature_reader.read_string()?;
+    let signature_blob = signature_reader.read_string()?;
+
+    match (host_key_algorithm, signature_algorithm) {
+        (b"ssh-ed25519", b"ssh-ed25519") => {
+            signature::UnparsedPublicKey::new(&ED25519, host_key_reader.read_string()?)
+                .verify(message, signature_blob)
+                .or(Err(()))
+        }
+        (b"ecdsa-sha2-nistp256", b"ecdsa-sha2-nistp256") => {
+            host_key_reader.read_string()?;
+
+            let public_key = host_key_reader.read_string()?;
+
+            // Convert signature from SSH format (2 mpints) to fixed format (2 padded integers)
+            let mut blob_reader = SSHReader::new(signature_blob);
+            let mut fixed_signature = Vec::with_capacity(64);
+
+            for _ in 0..2 {
+                let integer = blob_reader.read_mpint()?;
+
+                if integer.len() > 32 {
+                    return Err(());
+                }
+
+                fixed_signature.extend(vec![0; 32 - integer.len()]);
+                fixed_signature.extend_from_slice(integer);
+            }
+
+            signature::UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key)
+                .verify(message, &fixed_signature)
+                .or(Err(()))
+        }
+        (b"ssh-rsa", b"rsa-sha2-256") | (b"ssh-rsa", b"rsa-sha2-512") => {
+            let exponent = host_key_reader.read_mpint()?;
+            let modulus = host_key_reader.read_mpint()?;
+
+            let parameters = if signature_algorithm == b"rsa-sha2-512" {
+                &RSA_PKCS1_2048_8192_SHA512
+            } else {
+                &RSA_PKCS1_2048_8192_SHA256
+            };
+
+            RsaPublicKeyComponents {
+                n: modulus,
+                e: exponent,
+            }
+            .verify(parameters, message, signature_blob)
+            .or(Err(()))
+        }
+        _ => {
+            debug!(
+                "unsupported ssh host key signature algorithm: {}",
+                String::from_utf8_lossy(signature_algorithm)
+            );
+
+            Err(())
+        }
+    }
+}
+
+fn make_kexinit(random: &SystemRandom) -> Result<Vec<u8>, ()> {
+    let mut cookie = [0; 16];
+
+    random.fill(&mut cookie).or(Err(()))?;
+
+    let mut kexinit = vec![MESSAGE_KEXINIT];
+
+    kexinit.extend_from_slice(&cookie);
+
+    for algorithms in &[
+        SSH_KEX_ALGORITHMS,
+        SSH_HOST_KEY_ALGORITHMS,
+        SSH_CIPHER_ALGORITHMS,
+        SSH_CIPHER_ALGORITHMS,
+        SSH_MAC_ALGORITHMS,
+        SSH_MAC_ALGORITHMS,
+        SSH_COMPRESSION_ALGORITHMS,
+        SSH_COMPRESSION_ALGORITHMS,
+        "",
+        "",
+    ] {
+        write_string(&mut kexinit, algorithms.as_bytes());
+    }
+
+    // Append 'first_kex_packet_follows' (false) and reserved field
+    kexinit.push(0);
+    kexinit.extend_from_slice(&0_u32.to_be_bytes());
+
+    Ok(kexinit)
+}
+
+fn read_packet_of<S: Read + Write>(stream: &mut S, message: u8) -> Result<Vec<u8>, ()> {
+    for _ in 0..SSH_PACKETS_MAXIMUM {
+        let payload = read_packet(stream)?;
+
+        match payload.first() {
+            Some(&MESSAGE_IGNORE) | Some(&MESSAGE_DEBUG) => {}
+            Some(&MESSAGE_DISCONNECT) => {
+                debug!("ssh server disconnected during key exchange");
+
+                return Err(());
+            }
+            Some(&value) if value == message => return Ok(payload),
+            _ => return Err(()),
+        }
+    }
+
+    Err(())
+}
+
+fn read_packet<S: Read + Write>(stream: &mut S) -> Result<Vec<u8>, ()> {
+    // Notice: packets are not encrypted nor authenticated until keys are exchanged, which \
+    //   means that they can be read as-is.
+    let mut header = [0; 5];
+
+    stream.read_exact(&mut header).or(Err(()))?;
+
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let padding_size = header[4] as usize;
+
+    if size > SSH_PACKET_MAXIMUM_SIZE || padding_size + 1 > size {
+        return Err(());
+    }
+
+    let mut packet = vec![0; size - 1];
+
+    stream.read_exact(&mut packet).or(Err(()))?;
+    packet.truncate(size - 1 - padding_size);
+
+    Ok(packet)
+}
+
+fn write_packet<S: Read + Write>(stream: &mut S, payload: &[u8]) -> Result<(), ()> {
+    // Pad packet to a multiple of 8 bytes (with at least 4 bytes of padding)
+    let mut padding_size = 8 - (payload.len() + 5) % 8;
+
+    if padding_size < 4 {
+        padding_size += 8;
+    }
+
+    let mut packet = Vec::with_capacity(5 + payload.len() + padding_size);
+
+    packet.extend_from_slice(&((payload.len() + padding_size + 1) as u32).to_be_bytes());
+    packet.push(padding_size as u8);
+    packet.extend_from_slice(payload);
+    packet.extend(vec![0; padding_size]);
+
+    stream
+        .write_all(&packet)
+        .and_then(|_| stream.flush())
+        .or(Err(()))
+}
+
+fn write_disconnect<S: Read + Write>(stream: &mut S) -> Result<(), ()> {
+    let mut disconnect = vec![MESSAGE_DISCONNECT];
+
+    // Reason code: 'SSH_DISCONNECT_BY_APPLICATION'
+    disconnect.extend_from_slice(&11_u32.to_be_bytes());
+
+    write_string(&mut disconnect, b"probe done");
+    write_string(&mut disconnect, b"");
+
+    write_packet(stream, &disconnect)
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &[u8]) {
+    buffer.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(value);
+}
+
+fn write_mpint(buffer: &mut Vec<u8>, value: &[u8]) {
+    let value = &value[value.iter().take_while(|byte| **byte == 0).count()..];
+
+    // Prepend a zero byte if the high bit is set (as the integer would be negative otherwise)
+    if value.first().map(|byte| byte & 0x80 != 0) == Some(true) {
+        buffer.extend_from_slice(&((value.len() + 1) as u32).to_be_bytes());
+        buffer.push(0);
+        buffer.extend_from_slice(value);
+    } else {
+        write_string(buffer, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    const SERVER_IDENTIFICATION: &'static str = "SSH-2.0-mock";
+
+    fn serve(kex_algorithms: &'static str) -> (TcpStream, String, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let random = SystemRandom::new();
+
+        let host_key_pair =
+            Ed25519KeyPair::from_pkcs8(Ed25519KeyPair::generate_pkcs8(&random).unwrap().as_ref())
+                .unwrap();
+
+        let mut host_key = Vec::new();
+
+        write_string(&mut host_key, b"ssh-ed25519");
+        write_string(&mut host_key, host_key_pair.public_key().as_ref());
+
+        let fingerprint = format!(
+            "SHA256:{}",
+            base64_encoder.encode(digest(&SHA256, &host_key))
+        );
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            stream
+                .write_all(format!("{}\r\n", SERVER_IDENTIFICATION).as_bytes())
+                .unwrap();
+
+            let client_identification = read_identification(&mut stream).unwrap();
+
+            // Exchange key exchange initialization messages
+            let mut server_kexinit = vec![MESSAGE_KEXINIT];
+
+            server_kexinit.extend_from_slice(&[0; 16]);
+
+            for algorithms in &[
+                kex_algorithms,
+                "ssh-ed25519",
+                "",
+                "",
+                "",
+                "",
+                "",
+                "",
+                "",
+                "",
+            ] {
+                write_string(&mut server_kexinit, algorithms.as_bytes());
+            }
+
+            server_kexinit.extend_from_slice(&[0; 5]);
+
+            write_packet(&mut stream, &server_kexinit).unwrap();
+
+            let client_kexinit = read_packet_of(&mut stream, MESSAGE_KEXINIT).unwrap();
+
+            let kex_algorithm = match negotiate_kex(&server_kexinit).unwrap() {
+                Some(kex_algorithm) => kex_algorithm,
+                None => return,
+            };
+
+            // Reply with host key and ephemeral key (signing the exchange hash)
+            let ecdh_init = read_packet_of(&mut stream, MESSAGE_KEX_ECDH_INIT).unwrap();
+            let client_public_key = SSHReader::new(&ecdh_init[1..]).read_string().unwrap();
+
+            let private_key = EphemeralPrivateKey::generate(kex_algorithm, &random).unwrap();
+            let server_public_key = private_key.compute_public_key().unwrap();
+
+            let shared_secret = agree_ephemeral(
+                private_key,
+                &agreement::UnparsedPublicKey::new(kex_algorithm, client_public_key),
+                |secret| secret.to_vec(),
+            )
+            .unwrap();
+
+            let mut exchange_data = Vec::new();
+
+            write_string(&mut exchange_data, client_identification.as_bytes());
+            write_string(&mut exchange_data, SERVER_IDENTIFICATION.as_bytes());
+            write_string(&mut exchange_data, &client_kexinit);
+            write_string(&mut exchange_data, &server_kexinit);
+            write_string(&mut exchange_data, &host_key);
+            write_string(&mut exchange_data, client_public_key);
+            write_string(&mut exchange_data, server_public_key.as_ref());
+            write_mpint(&mut exchange_data, &shared_secret);
+
+            let exchange_hash = digest(&SHA256, &exchange_data);
+            let mut signature = Vec::new();
+
+            write_string(&mut signature, b"ssh-ed25519");
+            write_string(
+                &mut signature,
+                host_key_pair.sign(exchange_hash.as_ref()).as_ref(),
+            );
+
+            let mut ecdh_reply = vec![MESSAGE_KEX_ECDH_REPLY];
+
+            write_string(&mut ecdh_reply, &host_key);
+            write_string(&mut ecdh_reply, server_public_key.as_ref());
+            write_string(&mut ecdh_reply, &signature);
+
+            write_packet(&mut stream, &ecdh_reply).unwrap();
+        });
+
+        (TcpStream::connect(address).unwrap(), fingerprint, server)
+    }
+
+    fn check_kex(kex_algorithms: &'static str) {
+        let (stream, fingerprint, server) = serve(kex_algorithms);
+
+        assert!(matches!(
+            check(stream, &Some(fingerprint)),
+            Ok(SSHHostKeyStatus::Matching)
+        ));
+
+        server.join().unwrap();
+
+        let (stream, _, server) = serve(kex_algorithms);
+
+        assert!(matches!(
+            check(stream, &Some("SHA256:other".to_string())),
+            Ok(SSHHostKeyStatus::Mismatching)
+        ));
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn it_exchanges_keys_over_curve25519() {
+        check_kex("curve25519-sha256");
+    }
+
+    #[test]
+    fn it_exchanges_keys_over_nistp256() {
+        check_kex("diffie-hellman-group14-sha256,ecdh-sha2-nistp256");
+    }
+
+    #[test]
+    fn it_reports_unsupported_key_exchange() {
+        let (stream, fingerprint, server) = serve("diffie-hellman-group14-sha256");
+
+        assert!(matches!(
+            check(stream, &Some(fingerprint)),
+            Ok(SSHHostKeyStatus::Unsupported)
+        ));
+
+        server.join().unwrap();
+    }
+}
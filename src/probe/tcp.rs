@@ -0,0 +1,67 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use url::Url;
+
+use super::address::{AddressFamily, AddressPolicy};
+use super::bind::{connect as bind_connect, Bind};
+use super::engine::Engine;
+use super::map::{MapMetrics, MapServiceNode};
+use super::prober::{
+    acquire_dead_timeout, host_string, options as prober_options, ProbeOutcome, Prober,
+};
+
+#[derive(Debug)]
+pub struct TCPProber {
+    host: String,
+    port: u16,
+    addresses: AddressPolicy,
+    family: Option<AddressFamily>,
+    bind: Option<Bind>,
+}
+
+impl Prober for TCPProber {
+    fn parse(url: &Url) -> Result<TCPProber, ()> {
+        let options = prober_options(url);
+
+        match (url.host(), url.port()) {
+            (Some(host), Some(port)) => Ok(TCPProber {
+                host: host_string(host),
+                port: port,
+                addresses: AddressPolicy::from_options(&options)?,
+                family: AddressFamily::from_options(&options)?,
+                bind: Bind::from_options(&options)?,
+            }),
+            _ => Err(()),
+        }
+    }
+
+    fn probe(
+        &self,
+        engine: &Engine,
+        _node: &MapServiceNode,
+        metrics: &Option<MapMetrics>,
+    ) -> ProbeOutcome {
+        let dead_timeout = acquire_dead_timeout(metrics);
+        let bind = engine.bind_for(&self.bind);
+
+        engine.probe_addresses(
+            &self.host,
+            self.port,
+            self.family,
+            self.addresses,
+            bind,
+            |address| {
+                debug!("prober poll will fire for tcp target: {}", address);
+
+                match bind_connect(bind, &address, dead_timeout) {
+                    Ok(_) => ProbeOutcome::healthy(),
+                    Err(err) => ProbeOutcome::dead(format!("connection failed: {}", err)),
+                }
+            },
+        )
+    }
+}
@@ -0,0 +1,280 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+#[cfg(feature = "probe-http")]
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+#[cfg(feature = "probe-http")]
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+#[cfg(feature = "probe-http")]
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::ServerName;
+#[cfg(feature = "probe-http")]
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+#[cfg(feature = "probe-http")]
+use rustls::{DigitallySignedStruct, Error, SignatureScheme};
+
+use std::convert::TryFrom;
+use std::net::TcpStream;
+#[cfg(feature = "probe-http")]
+use std::path::Path;
+use std::sync::Arc;
+#[cfg(feature = "probe-http")]
+use std::sync::PoisonError;
+
+#[cfg(feature = "probe-http")]
+use super::engine::Engine;
+
+pub type TLSStream = StreamOwned<ClientConnection, TcpStream>;
+
+#[cfg(feature = "probe-grpc")]
+const TLS_ALPN_PROTOCOL_HTTP2: &'static [u8] = b"h2";
+
+#[cfg(any(feature = "probe-mail", feature = "probe-websocket"))]
+lazy_static! {
+    static ref TLS_CLIENT_CONFIG: Arc<ClientConfig> = Arc::new(make_client_config(Vec::new()));
+}
+
+#[cfg(feature = "probe-grpc")]
+lazy_static! {
+    static ref TLS_CLIENT_CONFIG_HTTP2: Arc<ClientConfig> =
+        Arc::new(make_client_config(vec![TLS_ALPN_PROTOCOL_HTTP2.to_vec()]));
+}
+
+#[cfg(feature = "probe-http")]
+#[derive(Debug)]
+struct InsecureCertificateVerifier(Arc<CryptoProvider>);
+
+#[cfg(any(feature = "probe-mail", feature = "probe-websocket"))]
+pub fn connect(host: &str, stream: TcpStream) -> Result<TLSStream, ()> {
+    connect_with_config(host, stream, TLS_CLIENT_CONFIG.clone())
+}
+
+#[cfg(feature = "probe-grpc")]
+pub fn connect_http2(host: &str, stream: TcpStream) -> Result<TLSStream, ()> {
+    connect_with_config(host, stream, TLS_CLIENT_CONFIG_HTTP2.clone())
+}
+
+#[cfg(feature = "probe-http")]
+pub fn connect_host(engine: &Engine, host: &str, stream: TcpStream) -> Result<TLSStream, ()> {
+    // Notice: this applies the local TLS configuration (ie. extra CA bundles, client \
+    //   certificates and disabled verification) that matches the host.
+    connect_with_config(host, stream, acquire_host_config(engine, host)?)
+}
+
+#[cfg(feature = "probe-http")]
+fn acquire_host_config(engine: &Engine, host: &str) -> Result<Arc<ClientConfig>, ()> {
+    // Notice: the lock may have been poisoned by a panicking probe, though the cache it \
+    //   guards stays usable (configurations are inserted once fully built).
+    let mut configs = engine
+        .tls_host_configs
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    // Notice: host configurations are built once, and cached afterwards (as reading the CA \
+    //   bundles and client certificates upon every poll would be wasteful)
+    if let Some(config) = configs.get(host) {
+        return Ok(config.clone());
+    }
+
+    let config = Arc::new(make_host_config(engine, host)?);
+
+    configs.insert(host.to_owned(), config.clone());
+
+    Ok(config)
+}
+
+#[cfg(feature = "probe-http")]
+fn make_host_config(engine: &Engine, host: &str) -> Result<ClientConfig, ()> {
+    let tls = engine.tls.as_ref();
+    let builder = ClientConfig::builder();
+
+    // Verify server certificates? (unless explicitly disabled for host)
+    let is_insecure = tls
+        .map(|tls_inner| {
+            tls_inner
+                .insecure_skip_verify
+                .iter()
+                .any(|pattern| matches_host_pattern(pattern, host))
+        })
+        .unwrap_or(false);
+
+    let builder = if is_insecure == true {
+        warn!(
+            "tls certificate verification is disabled for host: {} (this is insecure)",
+            host
+        );
+
+        let provider = builder.crypto_provider().clone();
+
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(InsecureCertificateVerifier(provider)))
+    } else {
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+
+        if let Some(tls_inner) = tls {
+            for ca_bundle in &tls_inner.ca_bundles {
+                for certificate in read_certificates(ca_bundle)? {
+                    roots.add(certificate).map_err(|err| {
+                        error!("invalid tls ca in bundle: {:?} (error: {})", ca_bundle, err);
+                    })?;
+                }
+            }
+        }
+
+        builder.with_root_certificates(roots)
+    };
+
+    // Authenticate with a client certificate? (host-specific ones take precedence over the \
+    //   global one)
+    let client_identity = tls.and_then(|tls_inner| {
+        tls_inner
+            .clients
+            .iter()
+            .find(|client| {
+                client
+                    .hosts
+                    .iter()
+                    .any(|pattern| matches_host_pattern(pattern, host))
+            })
+            .map(|client| (&client.cert, &client.key))
+            .or(tls_inner.client.as_ref().map(|(cert, key)| (cert, key)))
+    });
+
+    match client_identity {
+        Some((cert, key)) => {
+            let key = PrivateKeyDer::from_pem_file(key).map_err(|err| {
+                error!("could not read tls client key: {:?} (error: {})", key, err);
+            })?;
+
+            builder
+                .with_client_auth_cert(read_certificates(cert)?, key)
+                .map_err(|err| {
+                    error!(
+                        "invalid tls client certificate: {:?} (error: {})",
+                        cert, err
+                    );
+                })
+        }
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+#[cfg(feature = "probe-http")]
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, ()> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| {
+            error!(
+                "could not read tls certificates: {:?} (error: {})",
+                path, err
+            );
+        })
+}
+
+#[cfg(feature = "probe-http")]
+pub fn matches_host_pattern(pattern: &str, host: &str) -> bool {
+    // Patterns are either an exact host, a wildcard on subdomains (eg. '*.example.com'), or a \
+    //   catch-all wildcard
+    let (pattern, host) = (pattern.to_lowercase(), host.to_lowercase());
+
+    if pattern == "*" {
+        true
+    } else if let Some(domain) = pattern.strip_prefix("*.") {
+        host.ends_with(domain) == true
+            && host.len() > domain.len()
+            && host[..(host.len() - domain.len())].ends_with('.') == true
+    } else {
+        pattern == host
+    }
+}
+
+#[cfg(any(
+    feature = "probe-mail",
+    feature = "probe-grpc",
+    feature = "probe-websocket"
+))]
+fn make_client_config(alpn_protocols: Vec<Vec<u8>>) -> ClientConfig {
+    let mut config = ClientConfig::builder()
+        .with_root_certificates(RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        })
+        .with_no_client_auth();
+
+    config.alpn_protocols = alpn_protocols;
+
+    config
+}
+
+fn connect_with_config(
+    host: &str,
+    stream: TcpStream,
+    config: Arc<ClientConfig>,
+) -> Result<TLSStream, ()> {
+    // Notice: the TLS handshake is lazily performed upon the first read or write on the \
+    //   returned stream, which means that handshake errors surface as I/O errors.
+    let server_name = ServerName::try_from(host.to_owned()).map_err(|err| {
+        debug!("invalid tls server name: {} (error: {})", host, err);
+    })?;
+
+    let session = ClientConnection::new(config, server_name).map_err(|err| {
+        debug!(
+            "could not create tls session for: {} (error: {})",
+            host, err
+        );
+    })?;
+
+    Ok(StreamOwned::new(session, stream))
+}
+
+#[cfg(feature = "probe-http")]
+impl ServerCertVerifier for InsecureCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
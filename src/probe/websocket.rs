@@ -0,0 +1,572 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use base64::engine::general_purpose::STANDARD as base64_encoder;
+use base64::Engine as Base64Engine;
+use sha1::{Digest, Sha1};
+use url::Url;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
+
+use super::address::{AddressFamily, AddressPolicy};
+use super::bind::{connect as bind_connect, Bind};
+use super::body::{check_http_body, has_http_body_checks};
+use super::engine::Engine;
+use super::map::{MapMetrics, MapServiceNode};
+use super::prober::{
+    acquire_dead_timeout, host_string, options as prober_options, ProbeOutcome, Prober,
+};
+use super::tls::connect as tls_connect;
+
+const WEBSOCKET_ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const WEBSOCKET_HANDSHAKE_MAXIMUM_SIZE: usize = 16384;
+const WEBSOCKET_MESSAGE_MAXIMUM_SIZE: usize = 1048576;
+const WEBSOCKET_FRAMES_MAXIMUM: usize = 64;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+lazy_static! {
+    static ref WEBSOCKET_HTTP_HEADER_USERAGENT: String =
+        format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+}
+
+#[derive(Debug)]
+pub struct WebSocketProber {
+    host: String,
+    port: u16,
+    resource: String,
+    secure: bool,
+    addresses: AddressPolicy,
+    family: Option<AddressFamily>,
+    bind: Option<Bind>,
+}
+
+struct WebSocketFrame {
+    is_final: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl Prober for WebSocketProber {
+    fn parse(url: &Url) -> Result<WebSocketProber, ()> {
+        // Generate the resource to be requested upon upgrade (ie. path with query, if any)
+        let mut resource = url.path().to_owned();
+
+        if let Some(query) = url.query() {
+            resource.push('?');
+            resource.push_str(query);
+        }
+
+        let options = prober_options(url);
+
+        match (url.host(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => Ok(WebSocketProber {
+                host: host_string(host),
+                port: port,
+                resource: resource,
+                secure: url.scheme() == "wss",
+                addresses: AddressPolicy::from_options(&options)?,
+                family: AddressFamily::from_options(&options)?,
+                bind: Bind::from_options(&options)?,
+            }),
+            _ => Err(()),
+        }
+    }
+
+    fn probe(
+        &self,
+        engine: &Engine,
+        node: &MapServiceNode,
+        metrics: &Option<MapMetrics>,
+    ) -> ProbeOutcome {
+        let (host, port, resource) = (self.host.as_str(), self.port, self.resource.as_str());
+
+        // Unpack message to send, and reply body checks (applied to the reply message)
+        let message = node
+            .websocket
+            .as_ref()
+            .and_then(|websocket_inner| websocket_inner.message.to_owned());
+
+        let await_reply = message.is_some() == true || has_http_body_checks(&node.http) == true;
+
+        let dead_timeout = acquire_dead_timeout(metrics);
+        let bind = engine.bind_for(&self.bind);
+
+        engine.probe_addresses(host, port, self.family, self.addresses, bind, |address| {
+            debug!("prober poll will fire for websocket target: {}", address);
+
+            let stream = match bind_connect(bind, &address, dead_timeout) {
+                Ok(stream) => stream,
+                Err(err) => return ProbeOutcome::dead(format!("connection failed: {}", err)),
+            };
+
+            if stream.set_read_timeout(Some(dead_timeout)).is_err()
+                || stream.set_write_timeout(Some(dead_timeout)).is_err()
+            {
+                return ProbeOutcome::dead("could not set socket timeouts");
+            }
+
+            let reply = if self.secure == true {
+                tls_connect(host, stream).and_then(|stream| {
+                    converse(stream, host, port, resource, &message, await_reply)
+                })
+            } else {
+                converse(stream, host, port, resource, &message, await_reply)
+            };
+
+            match reply {
+                Ok(Some(reply_inner)) => {
+                    debug!(
+                        "got prober poll reply for websocket target: {} ({} bytes)",
+                        address,
+                        reply_inner.len()
+                    );
+
+                    check_http_body(resource, &reply_inner, &node.http, "reply")
+                }
+                Ok(None) => ProbeOutcome::healthy(),
+                Err(_) => {
+                    debug!(
+                        "prober poll conversation failed for websocket target: {}",
+                        address
+                    );
+
+                    ProbeOutcome::dead("conversation failed")
+                }
+            }
+        })
+    }
+}
+
+fn converse<S: Read + Write>(
+    mut stream: S,
+    host: &str,
+    port: u16,
+    resource: &str,
+    message: &Option<String>,
+    await_reply: bool,
+) -> Result<Option<Vec<u8>>, ()> {
+    // Perform opening handshake
+    handshake(&mut stream, host, port, resource)?;
+
+    debug!("upgraded websocket connection for: {}:{}", host, port);
+
+    // Send message? (if any)
+    if let Some(ref message_inner) = message {
+        write_frame(&mut stream, OPCODE_TEXT, message_inner.as_bytes())?;
+    }
+
+    // Wait for reply? (ie. the first data message received)
+    let reply = if await_reply == true {
+        Some(read_message(&mut stream)?)
+    } else {
+        None
+    };
+
+    close(&mut stream);
+
+    Ok(reply)
+}
+
+fn close<S: Read + Write>(stream: &mut S) {
+    // Perform closing handshake (the close reply from the server is awaited, up to the read \
+    //   timeout, though not receiving it does not fail the probe, since the connection already \
+    //   proved to be working)
+    if write_frame(stream, OPCODE_CLOSE, &1000_u16.to_be_bytes()).is_err() {
+        return;
+    }
+
+    for _ in 0..WEBSOCKET_FRAMES_MAXIMUM {
+        match read_frame(stream) {
+            Ok(frame) if frame.opcode == OPCODE_CLOSE => return,
+            Ok(_) => {
+                // Ignore frames sent before the close reply (eg. late messages)
+            }
+            Err(_) => break,
+        }
+    }
+
+    debug!("websocket server did not reply to close");
+}
+
+fn handshake<S: Read + Write>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+    resource: &str,
+) -> Result<(), ()> {
+    let key = generate_key();
+
+    // Notice: IPv6 hosts must be wrapped in brackets in the host header
+    let host_header = if host.contains(':') == true {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\nUser-Agent: {}\r\n\r\n",
+        resource, host_header, key, &*WEBSOCKET_HTTP_HEADER_USERAGENT
+    );
+
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|_| stream.flush())
+        .or(Err(()))?;
+
+    // Read response head (byte-per-byte, as the server may send frames right after the head)
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+
+    while head.ends_with(b"\r\n\r\n") == false {
+        if head.len() > WEBSOCKET_HANDSHAKE_MAXIMUM_SIZE {
+            return Err(());
+        }
+
+        stream.read_exact(&mut byte).or(Err(()))?;
+
+        head.push(byte[0]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+
+    // Check for upgrade status
+    let status_code = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .unwrap_or("");
+
+    if status_code != "101" {
+        debug!(
+            "websocket upgrade refused for: {}:{} with status: {}",
+            host, port, status_code
+        );
+
+        return Err(());
+    }
+
+    // Check for accept key (proves that the server understood the upgrade request)
+    let mut hasher = Sha1::new();
+
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_ACCEPT_GUID.as_bytes());
+
+    let expected_accept = base64_encoder.encode(hasher.finalize());
+
+    let has_accept = lines.any(|line| {
+        let mut parts = line.splitn(2, ':');
+
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => {
+                name.trim().eq_ignore_ascii_case("sec-websocket-accept")
+                    && value.trim() == expected_accept
+            }
+            _ => false,
+        }
+    });
+
+    if has_accept == true {
+        Ok(())
+    } else {
+        debug!(
+            "websocket upgrade has invalid accept for: {}:{}",
+            host, port
+        );
+
+        Err(())
+    }
+}
+
+fn read_message<S: Read + Write>(stream: &mut S) -> Result<Vec<u8>, ()> {
+    let mut message = Vec::new();
+    let mut is_message_started = false;
+
+    for _ in 0..WEBSOCKET_FRAMES_MAXIMUM {
+        let frame = read_frame(stream)?;
+
+        match frame.opcode {
+            // Notice: orphan continuation frames are ignored (they fall in the catch-all case)
+            OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION
+                if frame.opcode != OPCODE_CONTINUATION || is_message_started == true =>
+            {
+                is_message_started = true;
+
+                message.extend_from_slice(&frame.payload);
+
+                if message.len() > WEBSOCKET_MESSAGE_MAXIMUM_SIZE {
+                    return Err(());
+                }
+
+                if frame.is_final == true {
+                    return Ok(message);
+                }
+            }
+            OPCODE_PING => {
+                write_frame(stream, OPCODE_PONG, &frame.payload)?;
+            }
+            OPCODE_CLOSE => {
+                debug!("websocket server closed connection before reply");
+
+                return Err(());
+            }
+            _ => {
+                // Ignore other frames (eg. unsolicited pongs)
+            }
+        }
+    }
+
+    Err(())
+}
+
+fn read_frame<S: Read + Write>(stream: &mut S) -> Result<WebSocketFrame, ()> {
+    let mut header = [0; 2];
+
+    stream.read_exact(&mut header).or(Err(()))?;
+
+    // Notice: servers must never mask frames, hence the mask bit is ignored here.
+    let size = match header[1] & 0x7f {
+        126 => {
+            let mut size = [0; 2];
+
+            stream.read_exact(&mut size).or(Err(()))?;
+
+            u16::from_be_bytes(size) as usize
+        }
+        127 => {
+            let mut size = [0; 8];
+
+            stream.read_exact(&mut size).or(Err(()))?;
+
+            u64::from_be_bytes(size) as usize
+        }
+        size => size as usize,
+    };
+
+    if size > WEBSOCKET_MESSAGE_MAXIMUM_SIZE {
+        return Err(());
+    }
+
+    let mut payload = vec![0; size];
+
+    stream.read_exact(&mut payload).or(Err(()))?;
+
+    Ok(WebSocketFrame {
+        is_final: header[0] & 0x80 != 0,
+        opcode: header[0] & 0x0f,
+        payload: payload,
+    })
+}
+
+fn write_frame<S: Read + Write>(stream: &mut S, opcode: u8, payload: &[u8]) -> Result<(), ()> {
+    let mut frame = Vec::with_capacity(14 + payload.len());
+
+    frame.push(0x80 | opcode);
+
+    // Notice: clients must always mask frames they send (with a random key)
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(0x80 | 127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+
+    let mask = (RandomState::new().build_hasher().finish() as u32).to_be_bytes();
+
+    frame.extend_from_slice(&mask);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| byte ^ mask[index % 4]),
+    );
+
+    stream
+        .write_all(&frame)
+        .and_then(|_| stream.flush())
+        .or(Err(()))
+}
+
+fn generate_key() -> String {
+    // Generate a random 16-byte nonce (randomly-seeded hashers are a handy source of randomness \
+    //   that avoids depending on an additional library)
+    let mut nonce = Vec::with_capacity(16);
+
+    nonce.extend_from_slice(&RandomState::new().build_hasher().finish().to_be_bytes());
+    nonce.extend_from_slice(&RandomState::new().build_hasher().finish().to_be_bytes());
+
+    base64_encoder.encode(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::super::status::Status;
+
+    fn serve(
+        is_accept_valid: bool,
+        reply: &'static str,
+    ) -> (WebSocketProber, thread::JoinHandle<(Vec<u8>, bool)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            // Read upgrade request head
+            let mut head = Vec::new();
+            let mut byte = [0; 1];
+
+            while head.ends_with(b"\r\n\r\n") == false {
+                stream.read_exact(&mut byte).unwrap();
+
+                head.push(byte[0]);
+            }
+
+            let key = String::from_utf8_lossy(&head)
+                .lines()
+                .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+                .unwrap()
+                .to_owned();
+
+            let mut hasher = Sha1::new();
+
+            hasher.update(key.as_bytes());
+            hasher.update(WEBSOCKET_ACCEPT_GUID.as_bytes());
+
+            let accept = if is_accept_valid == true {
+                base64_encoder.encode(hasher.finalize())
+            } else {
+                base64_encoder.encode(b"invalid")
+            };
+
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                            Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                        accept
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+
+            if is_accept_valid == false {
+                return (Vec::new(), false);
+            }
+
+            // Read message, then reply to it
+            let message = read_client_frame(&mut stream).1;
+
+            write_server_frame(&mut stream, OPCODE_TEXT, reply.as_bytes());
+
+            // Read close, then reply to it
+            let has_close = read_client_frame(&mut stream).0 == OPCODE_CLOSE;
+
+            write_server_frame(&mut stream, OPCODE_CLOSE, &1000_u16.to_be_bytes());
+
+            (message, has_close)
+        });
+
+        let prober = WebSocketProber::parse(
+            &Url::parse(&format!("ws://127.0.0.1:{}/socket", address.port())).unwrap(),
+        )
+        .unwrap();
+
+        (prober, server)
+    }
+
+    fn read_client_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0; 6];
+
+        stream.read_exact(&mut header).unwrap();
+
+        let mut payload = vec![0; (header[1] & 0x7f) as usize];
+
+        stream.read_exact(&mut payload).unwrap();
+
+        let payload = payload
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| byte ^ header[2 + index % 4])
+            .collect();
+
+        (header[0] & 0x0f, payload)
+    }
+
+    fn write_server_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) {
+        let mut frame = vec![0x80 | opcode, payload.len() as u8];
+
+        frame.extend_from_slice(payload);
+
+        stream.write_all(&frame).unwrap();
+    }
+
+    fn node(body: &str) -> MapServiceNode {
+        serde_json::from_str(&format!(
+            r#"{{"id": "node", "mode": "local", "websocket": {{"message": "ping"}}, "http": {{"body": {}}}}}"#,
+            body
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn it_converses_and_closes_cleanly() {
+        let (prober, server) = serve(true, "pong");
+
+        let outcome = prober.probe(
+            &Engine::builder().build(),
+            &node(r#"{"healthy_match": "pong"}"#),
+            &None,
+        );
+
+        assert_eq!(outcome.status, Status::Healthy);
+        assert_eq!(server.join().unwrap(), (b"ping".to_vec(), true));
+    }
+
+    #[test]
+    fn it_fails_on_invalid_accept() {
+        let (prober, server) = serve(false, "pong");
+
+        let outcome = prober.probe(&Engine::builder().build(), &node("{}"), &None);
+
+        assert_eq!(outcome.status, Status::Dead);
+        assert_eq!(outcome.reason, Some("conversation failed".to_string()));
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn it_checks_reply_against_body_rules() {
+        let (prober, server) = serve(true, "error: database down");
+
+        let outcome = prober.probe(
+            &Engine::builder().build(),
+            &node(r#"{"healthy_match": "pong", "dead_match": "error"}"#),
+            &None,
+        );
+
+        assert_eq!(outcome.status, Status::Dead);
+        assert_eq!(outcome.reason, Some("reply has dead match".to_string()));
+
+        server.join().unwrap();
+    }
+}
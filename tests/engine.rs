@@ -0,0 +1,89 @@
+// crisp-status-local
+//
+// Crisp Status local probe relay
+// Copyright: 2018, Crisp IM SAS
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+#![cfg(feature = "probe-tcp")]
+
+extern crate crisp_status_local;
+extern crate serde_json;
+
+use std::net::TcpListener;
+
+use crisp_status_local::probe::address::{AddressFamily, AddressPolicy};
+use crisp_status_local::probe::bind::Bind;
+use crisp_status_local::probe::map::MapServiceNode;
+use crisp_status_local::probe::prober::ProbeOutcome;
+use crisp_status_local::probe::replica::ReplicaURL;
+use crisp_status_local::probe::status::Status;
+use crisp_status_local::{Engine, EngineError};
+
+fn node() -> MapServiceNode {
+    serde_json::from_str(r#"{"id": "node", "mode": "local"}"#).unwrap()
+}
+
+#[test]
+fn probes_tcp_replica() {
+    let engine = Engine::builder().family(AddressFamily::IPv4).build();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let replica = ReplicaURL::parse_from(&format!("tcp://127.0.0.1:{}", port)).unwrap();
+
+    assert!(matches!(
+        replica.probe(&engine, &node(), &None).status,
+        Status::Healthy
+    ));
+
+    drop(listener);
+
+    let outcome = replica.probe(&engine, &node(), &None);
+
+    assert!(matches!(outcome.status, Status::Dead));
+    assert!(outcome.reason.is_some());
+}
+
+#[test]
+fn clamps_address_quorum() {
+    let addresses = vec![
+        "127.0.0.1:80".parse().unwrap(),
+        "127.0.0.2:80".parse().unwrap(),
+    ];
+
+    let outcome = AddressPolicy::Quorum(3).probe(addresses, |_| ProbeOutcome::healthy());
+
+    assert!(matches!(outcome.status, Status::Healthy));
+}
+
+#[test]
+fn rejects_invalid_settings() {
+    assert_eq!(
+        ReplicaURL::parse_from("unknown://127.0.0.1:22").unwrap_err(),
+        EngineError::InvalidReplica
+    );
+    assert_eq!(
+        ReplicaURL::parse_from("tcp://127.0.0.1:22#addresses=some").unwrap_err(),
+        EngineError::InvalidReplica
+    );
+    assert_eq!(
+        ReplicaURL::parse_from("ssh://127.0.0.1:22#mismatch=ignore").unwrap_err(),
+        EngineError::InvalidReplica
+    );
+    assert_eq!(
+        AddressPolicy::parse("quorum=0").unwrap_err(),
+        EngineError::InvalidAddressPolicy
+    );
+    assert_eq!(
+        AddressFamily::parse("ipv5").unwrap_err(),
+        EngineError::InvalidAddressFamily
+    );
+    assert_eq!(
+        Bind::parse("not/an/interface").unwrap_err(),
+        EngineError::InvalidBind
+    );
+    assert_eq!(
+        EngineError::InvalidBind.to_string(),
+        "invalid bind address or interface"
+    );
+}